@@ -0,0 +1,45 @@
+use std::net::IpAddr;
+use flate2::{Compression, write::GzEncoder};
+use http::Method;
+use reqwest::header::{CONTENT_TYPE, CONTENT_ENCODING, HeaderValue};
+use serde::Serialize;
+use crate::{Client, Device, Error};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DnsResponse {
+    pub question: DnsQuestion,
+    pub answers:  Vec<DnsAnswer>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub host: IpAddr,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DnsAnswer {
+    pub name:  String,
+    pub cname: Option<String>,
+    pub ip:    Option<IpAddr>,
+    pub ttl:   u32,
+}
+
+impl Client {
+    pub async fn dns(&self, device: &Device, dns: &[DnsResponse]) -> Result<(), Error> {
+        let cid = device.client_id();
+        let url = format!("{}?sid=0&sender_id={}", self.urls.dns, cid);
+
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut e, dns)?;
+        let dns = e.finish()?;
+
+        let mut request = self.request(Method::POST, &url)?;
+        request.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        request.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        request.body_mut().replace(dns.into());
+        self.send(request).await?;
+
+        Ok(())
+    }
+}
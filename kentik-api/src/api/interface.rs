@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+use http::Method;
+use reqwest::header::{CONTENT_TYPE, HeaderValue};
+use serde::{Serialize, Deserialize};
+use crate::{Client, Device, Error};
+
+// Interface update as sent by libkflow's UpdateInterfaces, a JSON
+// object keyed by interface name.
+#[derive(Clone, Default, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Interface {
+    #[serde(with = "crate::serde::str")]
+    pub index: u64,
+    pub alias: String,
+    pub desc:  String,
+    pub speed: u64,
+    #[serde(rename = "type")]
+    pub kind:  u64,
+}
+
+impl Client {
+    pub async fn update_interfaces(&self, device: &Device, interfaces: &HashMap<String, Interface>) -> Result<(), Error> {
+        let url  = format!("{}/company/{}/device/{}/interfaces", self.urls.internal, device.company_id, device.id);
+        let body = serde_json::to_vec(interfaces)?;
+
+        let mut request = self.request(Method::PUT, &url)?;
+        request.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        request.body_mut().replace(body.into());
+        self.send(request).await?;
+
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
 mod device;
+mod dns;
 mod flow;
+mod interface;
 
 pub use device::{Device, Column};
+pub use dns::{DnsAnswer, DnsQuestion, DnsResponse};
+pub use interface::Interface;
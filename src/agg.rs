@@ -13,7 +13,7 @@ use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
 use kentik_api::Client;
 use crate::args::opt;
 use crate::augment::Augment;
-use crate::collect::Record;
+use crate::collect::Message;
 use crate::combine::Combine;
 use crate::export::get_or_create_device;
 
@@ -84,12 +84,16 @@ async fn agent(sock: TcpStream, combine: Arc<Combine>) -> Result<()> {
     let mut length = LengthDelimitedCodec::new();
     length.set_max_frame_length(32 * 1024 * 1024);
     let framed = FramedRead::new(sock, length);
-    let format = SymmetricalJson::<Vec<Record>>::default();
+    let format = SymmetricalJson::<Message>::default();
 
     let mut codec = SymmetricallyFramed::new(framed, format);
 
-    while let Some(rs) = codec.try_next().await? {
-        combine.combine(rs);
+    while let Some(msg) = codec.try_next().await? {
+        match msg {
+            Message::Records(rs)     => combine.combine(rs),
+            Message::Dns(rs)         => combine.dns(rs),
+            Message::Links(node, ls) => combine.links(node, ls),
+        }
     }
 
     Ok(())
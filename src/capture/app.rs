@@ -0,0 +1,158 @@
+use serde::{Serialize, Deserialize};
+use super::flow::{Addr, Protocol};
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum App {
+    Unknown,
+    HTTP,
+    TLS,
+    DNS,
+    SSH,
+    Postgres,
+    MySQL,
+    Redis,
+    HTTP2,
+    Kafka,
+}
+
+impl App {
+    pub fn id(self) -> u32 {
+        match self {
+            App::Unknown  => 1,
+            App::HTTP     => 2,
+            App::TLS      => 3,
+            App::DNS      => 4,
+            App::SSH      => 5,
+            App::Postgres => 6,
+            App::MySQL    => 7,
+            App::Redis    => 8,
+            App::HTTP2    => 9,
+            App::Kafka    => 10,
+        }
+    }
+}
+
+// Classify a flow from the first bytes of a TCP or UDP payload.
+pub fn classify(protocol: Protocol, payload: &[u8]) -> App {
+    match protocol {
+        Protocol::TCP => tcp(payload),
+        Protocol::UDP => udp(payload),
+        _             => App::Unknown,
+    }
+}
+
+// Fall back to well-known ports when the payload was inconclusive,
+// preferring the lower port as the service side.
+pub fn port(protocol: Protocol, src: Addr, dst: Addr) -> App {
+    let (a, b) = match src.port < dst.port {
+        true  => (src.port, dst.port),
+        false => (dst.port, src.port),
+    };
+
+    let port = |port| match (protocol, port) {
+        (Protocol::TCP, 80 | 8080)  => App::HTTP,
+        (Protocol::TCP, 443 | 8443) => App::TLS,
+        (_,             53)         => App::DNS,
+        (Protocol::TCP, 22)         => App::SSH,
+        (Protocol::TCP, 5432)       => App::Postgres,
+        (Protocol::TCP, 3306)       => App::MySQL,
+        (Protocol::TCP, 6379)       => App::Redis,
+        (Protocol::TCP, 50051)      => App::HTTP2,
+        (Protocol::TCP, 9092)       => App::Kafka,
+        _                           => App::Unknown,
+    };
+
+    match port(a) {
+        App::Unknown => port(b),
+        app          => app,
+    }
+}
+
+fn tcp(payload: &[u8]) -> App {
+    if payload.is_empty() {
+        return App::Unknown;
+    }
+
+    if HTTP_METHODS.iter().any(|m| payload.starts_with(m)) || payload.starts_with(b"HTTP/1.") {
+        return App::HTTP;
+    }
+
+    if payload.starts_with(HTTP2_PREFACE) {
+        return App::HTTP2;
+    }
+
+    if payload.starts_with(b"SSH-") {
+        return App::SSH;
+    }
+
+    match payload {
+        &[0x16, 3, 0..=4, ..]                     => App::TLS,
+        &[_, _, _, 0, 10, ..]                     => mysql(payload),
+        &[b'*', b'1'..=b'9', ..]                  => redis(payload),
+        &[0, 0, _, _, 0, 3, 0, 0, ..]             => App::Postgres,
+        &[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f, ..] => App::Postgres,
+        &[0, _, _, _, 0, _, 0, _, ..]             => kafka(payload),
+        _                                         => App::Unknown,
+    }
+}
+
+// Standard query or response with a single question whose first label
+// is plausible.
+fn udp(payload: &[u8]) -> App {
+    match payload {
+        &[_, _, flags, _, 0, 1, _, _, _, _, _, _, len, ..]
+            if flags & DNS_OPCODE == 0 && (1..=63).contains(&len) => App::DNS,
+        _                                                         => App::Unknown,
+    }
+}
+
+// Server greeting: 3 byte length, sequence 0, protocol version 10 and
+// a NUL terminated version string.
+fn mysql(payload: &[u8]) -> App {
+    let len = u32::from_le_bytes([payload[0], payload[1], payload[2], 0]) as usize;
+    match len > 0 && payload[5..].contains(&0) && len + 4 >= payload.len() {
+        true  => App::MySQL,
+        false => App::Unknown,
+    }
+}
+
+// RESP array of bulk strings, e.g. "*1\r\n$4\r\nPING\r\n".
+fn redis(payload: &[u8]) -> App {
+    let n = payload.iter().position(|&b| b == b'\r').unwrap_or(0);
+    match payload.get(n..n+3) {
+        Some(b"\r\n$") => App::Redis,
+        _              => App::Unknown,
+    }
+}
+
+// Request header: size, api key, api version, correlation id and a
+// nullable client id string.
+fn kafka(payload: &[u8]) -> App {
+    let size    = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    let key     = payload[5];
+    let version = payload[7];
+    let client  = match payload.get(12..14) {
+        Some(&[a, b]) => i16::from_be_bytes([a, b]),
+        _             => return App::Unknown,
+    };
+
+    let valid = size >= 10 && size + 4 >= payload.len()
+        && key <= KAFKA_MAX_KEY && version <= KAFKA_MAX_VERSION
+        && client >= -1 && (client as isize) + 10 <= size as isize;
+
+    match valid {
+        true  => App::Kafka,
+        false => App::Unknown,
+    }
+}
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ", b"POST ", b"PUT ", b"HEAD ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ",
+];
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DNS_OPCODE: u8 = 0x78;
+
+const KAFKA_MAX_KEY:     u8 = 74;
+const KAFKA_MAX_VERSION: u8 = 16;
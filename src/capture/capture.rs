@@ -1,28 +1,62 @@
+use std::cmp::max;
 use std::convert::TryInto;
 use anyhow::{Result, anyhow};
 use log::{info, warn};
-use pcap::{Capture, Active};
-use crate::capture::{Config, Sample, sample};
+use pcap::{Capture, Active, Linktype};
+use crate::capture::{Config, Datalink, Sample, sample};
+use super::sample::FULL_SNAPLEN;
 
-pub fn capture(name: &str, dev: &str, cfg: &Config) -> Result<Capture<Active>> {
+const DATALINKS: &[(Linktype, Datalink)] = &[
+    (Linktype(1),   Datalink::Ethernet),
+    (Linktype(276), Datalink::SLL2),
+    (Linktype(113), Datalink::SLL),
+    (Linktype(12),  Datalink::Raw),
+];
+
+// Packets are captured up to FULL_SNAPLEN and the socket filter cuts
+// all but DNS and inspected ports to the snaplen. Without the filter
+// the capture is reopened at the snaplen and payloads are not whole.
+pub fn capture(name: &str, dev: &str, cfg: &Config) -> Result<(Capture<Active>, Datalink, bool)> {
+    let (cap, link) = open(dev, cfg, max(cfg.snaplen, FULL_SNAPLEN))?;
+
+    let (cap, link, whole) = match sample(&cap, cfg.sample.kernel(), cfg) {
+        Ok(())                         => (cap, link, true),
+        Err(e) if cfg.filter.is_some() => return Err(e),
+        Err(e)                         => {
+            warn!("socket filter on {} failed, not parsing payloads: {}", name, e);
+            drop(cap);
+            let (cap, link) = open(dev, cfg, cfg.snaplen)?;
+            (cap, link, false)
+        },
+    };
+
+    if let Sample::Rate(n) = cfg.sample {
+        info!("sampling {} at 1:{}", name, n);
+    }
+
+    if let Some(expr) = &cfg.filter {
+        info!("filtering {} with '{}'", name, expr);
+    }
+
+    Ok((cap, link, whole))
+}
+
+fn open(dev: &str, cfg: &Config, snaplen: u64) -> Result<(Capture<Active>, Datalink)> {
     let mut cap = Capture::from_device(dev)?
         .buffer_size(cfg.buffer_size as i32)
         .timeout(cfg.interval.as_millis().try_into()?)
-        .snaplen(cfg.snaplen as i32)
+        .snaplen(snaplen as i32)
         .promisc(cfg.promisc)
         .open()?;
 
-    match cap.list_datalinks()?.into_iter().find(|lt| lt.0 == 1) {
-        Some(linktype) => cap.set_datalink(linktype)?,
-        None           => return Err(anyhow!("not ethernet")),
-    }
-
-    if let Sample::Rate(n) = cfg.sample {
-        match sample(&cap, n) {
-            Ok(()) => info!("sampling {} at 1:{}", name, n),
-            Err(e) => warn!("sampling {} failed: {}", name, e),
-        }
-    }
+    let links = cap.list_datalinks()?;
+    let link  = match DATALINKS.iter().find(|(lt, _)| links.contains(lt)) {
+        Some(&(linktype, link)) => {
+            cap.set_datalink(linktype)?;
+            link
+        },
+        None => return Err(anyhow!("unsupported datalink")),
+    };
 
-    Ok(cap)
+    Ok((cap, link))
 }
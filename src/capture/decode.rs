@@ -1,33 +1,75 @@
-use pnet::packet::ethernet::EthernetPacket;
+use std::net::IpAddr;
+use std::sync::Arc;
+use pnet::packet::ethernet::{EthernetPacket, EtherType, EtherTypes};
 use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet as _;
 use pnet::util::MacAddr;
-use crate::packet::{self, Packet, Opaque};
+use crate::dns::{self, Response};
+use crate::packet::{self, Packet, Opaque, Vlans};
+use super::app::classify;
 use super::flow::*;
 use crate::packet::Transport::*;
 
-pub fn decode(mac: Option<MacAddr>, cap: pcap::Packet<'_>) -> Option<Flow> {
-    let ts  = cap.header.ts.into();
-    let eth = EthernetPacket::new(cap.data)?;
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Datalink {
+    Ethernet,
+    SLL,
+    SLL2,
+    Raw,
+}
 
-    let bytes = cap.header.len as usize - EthernetPacket::minimum_packet_size();
-    let (vlan, pkt) = packet::decode(&eth);
+// How much of a payload to parse, applications are classified from the
+// first bytes and TLS and HTTP metadata is only extracted on inspection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Parse {
+    None,
+    Classify,
+    Inspect,
+}
 
-    let pkt = pkt?;
+impl Datalink {
+    pub fn new(dlt: i32) -> Option<Self> {
+        match dlt {
+            1        => Some(Datalink::Ethernet),
+            113      => Some(Datalink::SLL),
+            276      => Some(Datalink::SLL2),
+            12 | 101 => Some(Datalink::Raw),
+            _        => None,
+        }
+    }
 
-    let eth = Ethernet {
-        src:  eth.get_source(),
-        dst:  eth.get_destination(),
-        vlan: vlan,
-    };
+    pub fn linktype(&self) -> i32 {
+        match self {
+            Datalink::Ethernet => 1,
+            Datalink::SLL      => 113,
+            Datalink::SLL2     => 276,
+            Datalink::Raw      => 12,
+        }
+    }
+}
 
-    let dir = match mac {
-        Some(mac) if mac == eth.dst => Direction::In,
-        Some(mac) if mac == eth.src => Direction::Out,
-        _                           => Direction::Unknown,
+pub fn decode<F: Fn(&Flow) -> Parse>(link: Datalink, mac: Option<MacAddr>, cap: pcap::Packet<'_>, parse: F) -> Option<Flow> {
+    let ts = cap.header.ts.into();
+
+    let (mut eth, dir, ethertype, n) = match link {
+        Datalink::Ethernet => ethernet(mac, cap.data)?,
+        Datalink::SLL      => sll(cap.data)?,
+        Datalink::SLL2     => sll2(cap.data)?,
+        Datalink::Raw      => raw(cap.data)?,
     };
 
+    let frame  = packet::decode(ethertype, &cap.data[n..]);
+    let bytes  = (cap.header.len as usize).saturating_sub(n + frame.outer);
+    let tunnel = frame.tunnel;
+    let mpls   = frame.mpls;
+
+    eth.vlans = frame.vlans;
+
+    let pkt  = frame.packet?;
+    let frag = pkt.fragment();
+
     pkt.transport(pkt.payload()).map(|transport| {
         let mut flow = match transport {
             TCP(ref p)   => tcp(eth, &pkt, p),
@@ -37,17 +79,115 @@ pub fn decode(mac: Option<MacAddr>, cap: pcap::Packet<'_>) -> Option<Flow> {
         };
 
         flow.timestamp = ts;
+        flow.start     = ts;
+        flow.end       = ts;
+        flow.packets   = 1;
         flow.bytes     = bytes;
         flow.direction = dir;
+        flow.tunnel    = tunnel;
+        flow.mpls      = mpls;
+
+        if let Some(frag) = frag {
+            flow.fragments = 1;
+            flow.fragment  = frag;
+        }
+
+        let payload = match transport {
+            TCP(ref p) => p.payload(),
+            UDP(ref p) => p.payload(),
+            _          => &[],
+        };
+
+        if !payload.is_empty() {
+            let parse = parse(&flow);
+            inspect(&mut flow, payload, parse);
+        }
 
         flow
     })
 }
 
+fn ethernet(mac: Option<MacAddr>, data: &[u8]) -> Option<(Ethernet, Direction, EtherType, usize)> {
+    let pkt = EthernetPacket::new(data)?;
+    let src = pkt.get_source();
+    let dst = pkt.get_destination();
+
+    let dir = match mac {
+        Some(mac) if mac == dst => Direction::In,
+        Some(mac) if mac == src => Direction::Out,
+        _                       => Direction::Unknown,
+    };
+
+    let eth = Ethernet {
+        src:   src,
+        dst:   dst,
+        vlans: Vlans::default(),
+    };
+
+    Some((eth, dir, pkt.get_ethertype(), EthernetPacket::minimum_packet_size()))
+}
+
+fn sll(data: &[u8]) -> Option<(Ethernet, Direction, EtherType, usize)> {
+    let hdr = data.get(..16)?;
+
+    let kind = u16::from_be_bytes([hdr[0], hdr[1]]);
+    let alen = u16::from_be_bytes([hdr[4], hdr[5]]);
+    let addr = &hdr[6..14];
+    let next = u16::from_be_bytes([hdr[14], hdr[15]]);
+
+    Some((cooked(alen, addr), direction(kind), EtherType(next), 16))
+}
+
+fn sll2(data: &[u8]) -> Option<(Ethernet, Direction, EtherType, usize)> {
+    let hdr = data.get(..20)?;
+
+    let next = u16::from_be_bytes([hdr[0], hdr[1]]);
+    let kind = hdr[10] as u16;
+    let alen = hdr[11] as u16;
+    let addr = &hdr[12..20];
+
+    Some((cooked(alen, addr), direction(kind), EtherType(next), 20))
+}
+
+fn raw(data: &[u8]) -> Option<(Ethernet, Direction, EtherType, usize)> {
+    let ethertype = match data.first()? >> 4 {
+        4 => EtherTypes::Ipv4,
+        6 => EtherTypes::Ipv6,
+        _ => return None,
+    };
+
+    Some((cooked(0, &[]), Direction::Unknown, ethertype, 0))
+}
+
+fn cooked(alen: u16, addr: &[u8]) -> Ethernet {
+    let src = match (alen, addr) {
+        (6, &[a, b, c, d, e, f, ..]) => MacAddr::new(a, b, c, d, e, f),
+        _                            => MacAddr::zero(),
+    };
+
+    Ethernet {
+        src:   src,
+        dst:   MacAddr::zero(),
+        vlans: Vlans::default(),
+    }
+}
+
+// SLL packet types, the kernel already knows which way a packet went.
+fn direction(kind: u16) -> Direction {
+    match kind {
+        PACKET_HOST      => Direction::In,
+        PACKET_BROADCAST => Direction::In,
+        PACKET_MULTICAST => Direction::In,
+        PACKET_OUTGOING  => Direction::Out,
+        _                => Direction::Unknown,
+    }
+}
+
 fn tcp(eth: Ethernet, p: &Packet, tcp: &TcpPacket) -> Flow {
     let seq    = tcp.get_sequence();
     let flags  = tcp.get_flags();
     let window = tcp_window(tcp);
+    let len    = p.transport_len().saturating_sub(tcp.get_data_offset() as usize * 4) as u32;
 
     Flow{
         protocol:  Protocol::TCP,
@@ -55,7 +195,8 @@ fn tcp(eth: Ethernet, p: &Packet, tcp: &TcpPacket) -> Flow {
         src:       Addr{addr: p.src(), port: tcp.get_source()},
         dst:       Addr{addr: p.dst(), port: tcp.get_destination()},
         tos:       p.tos(),
-        transport: Transport::TCP{ seq, flags, window },
+        transport: Transport::TCP{ seq, flags, window, len },
+        dns:       response(tcp.get_source(), p.dst(), tcp.payload().get(2..).unwrap_or(&[])),
         .. Default::default()
     }
 }
@@ -68,6 +209,7 @@ fn udp(eth: Ethernet, p: &Packet, udp: &UdpPacket) -> Flow {
         dst:       Addr{addr: p.dst(), port: udp.get_destination()},
         tos:       p.tos(),
         transport: Transport::UDP,
+        dns:       response(udp.get_source(), p.dst(), udp.payload()),
         .. Default::default()
     }
 }
@@ -97,3 +239,31 @@ fn ip(eth: Ethernet, p: &Packet, o: &Opaque) -> Flow {
         .. Default::default()
     }
 }
+
+// Classify the application and extract TLS and HTTP metadata.
+fn inspect(flow: &mut Flow, payload: &[u8], parse: Parse) {
+    if parse == Parse::None {
+        return;
+    }
+
+    flow.app = classify(flow.protocol, payload);
+
+    if parse == Parse::Inspect && flow.protocol == Protocol::TCP {
+        flow.tls  = packet::client_hello(payload).map(Arc::new);
+        flow.http = packet::http(payload).map(Arc::new);
+    }
+}
+
+// TCP carries DNS messages behind a two byte length prefix, responses
+// are only parsed when the message starts the captured segment.
+fn response(port: u16, host: IpAddr, payload: &[u8]) -> Option<Box<Response>> {
+    match port {
+        53 => dns::parse(host, payload).map(Box::new),
+        _  => None,
+    }
+}
+
+const PACKET_HOST:      u16 = 0;
+const PACKET_BROADCAST: u16 = 1;
+const PACKET_MULTICAST: u16 = 2;
+const PACKET_OUTGOING:  u16 = 4;
@@ -1,37 +1,63 @@
+use std::cmp::{max, min};
 use std::fmt;
 use std::mem;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use pnet::packet::tcp::TcpPacket;
 use pnet::util::MacAddr;
 use serde::{Serialize, Deserialize};
-use super::Timestamp;
+use crate::dns::Response;
+use crate::packet::{Fragment, Http, Mpls, Tls, Tunnel, Vlans};
+use super::{App, Timestamp};
 
 pub const FIN: u16 = 0b00001;
 pub const SYN: u16 = 0b00010;
 pub const RST: u16 = 0b00100;
 pub const ACK: u16 = 0b10000;
 
+pub const SIZE_BUCKETS: usize = 6;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Flow {
-    pub timestamp: Timestamp,
-    pub ethernet:  Ethernet,
-    pub protocol:  Protocol,
-    pub src:       Addr,
-    pub dst:       Addr,
-    pub tos:       u8,
-    pub transport: Transport,
-    pub packets:   usize,
-    pub fragments: u16,
-    pub bytes:     usize,
-    pub sample:    u32,
-    pub direction: Direction,
+    pub timestamp:   Timestamp,
+    pub start:       Timestamp,
+    pub end:         Timestamp,
+    pub closed:      bool,
+    pub ethernet:    Ethernet,
+    pub protocol:    Protocol,
+    pub src:         Addr,
+    pub dst:         Addr,
+    pub tos:         u8,
+    pub transport:   Transport,
+    pub packets:     usize,
+    pub fragments:   u16,
+    pub retransmits: u32,
+    pub sizes:       Sizes,
+    #[serde(skip)]
+    pub fragment:    Fragment,
+    pub bytes:       usize,
+    pub sample:      u32,
+    pub hashed:      bool,
+    pub direction:   Direction,
+    pub index:       u32,
+    pub tunnel:      Option<Tunnel>,
+    pub mpls:        Mpls,
+    pub rtt:         Rtt,
+    pub tls:         Option<Arc<Tls>>,
+    pub http:        Option<Arc<Http>>,
+    pub app:         App,
+    pub reverse:     Counter,
+    pub biflow:      Biflow,
+    #[serde(skip)]
+    pub dns:         Option<Box<Response>>,
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Ethernet {
-    pub src:  MacAddr,
-    pub dst:  MacAddr,
-    pub vlan: Option<u16>
+    pub src:   MacAddr,
+    pub dst:   MacAddr,
+    pub vlans: Vlans,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -51,7 +77,7 @@ pub struct Addr {
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum Transport {
     ICMP,
-    TCP  { seq: u32, flags: u16, window: Window },
+    TCP  { seq: u32, flags: u16, window: Window, len: u32 },
     UDP,
     Other,
 }
@@ -62,12 +88,36 @@ pub struct Window {
     pub scale: u8,
 }
 
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Rtt {
+    pub client: Duration,
+    pub server: Duration,
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Counter {
+    pub packets: usize,
+    pub bytes:   usize,
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Sizes {
+    pub min:  u32,
+    pub max:  u32,
+    pub hist: [u32; SIZE_BUCKETS],
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Biflow {
+    Off, Canonical, Initiator
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Key(pub Protocol, pub Addr, pub Addr);
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum Direction {
-    In, Out, Unknown
+    In, Out, Transit, Unknown
 }
 
 impl Flow {
@@ -75,6 +125,94 @@ impl Flow {
         Key(self.protocol, self.src, self.dst)
     }
 
+    pub fn merge(&mut self, flow: &Flow) {
+        let sample = max(self.sample.max(1), flow.sample.max(1));
+        self.resample(sample);
+
+        let resampled;
+        let flow = match flow.sample.max(1) < sample {
+            true  => {
+                let mut flow = flow.clone();
+                flow.resample(sample);
+                resampled = flow;
+                &resampled
+            },
+            false => flow,
+        };
+
+        self.bytes   += flow.bytes;
+        self.packets += flow.packets;
+        self.tos     |= flow.tos;
+
+        self.reverse.bytes   += flow.reverse.bytes;
+        self.reverse.packets += flow.reverse.packets;
+
+        self.fragments   = self.fragments.saturating_add(flow.fragments);
+        self.retransmits = self.retransmits.saturating_add(flow.retransmits);
+        self.sizes.merge(&flow.sizes);
+
+        self.start   = min(self.start, flow.start);
+        self.end     = max(self.end, flow.end);
+        self.closed |= flow.closed;
+
+        if !flow.rtt.total().is_zero() {
+            self.rtt = flow.rtt;
+        }
+
+        if flow.tls.is_some() {
+            self.tls = flow.tls.clone();
+        }
+
+        if self.app == App::Unknown {
+            self.app = flow.app;
+        }
+
+        self.http = merge_http(&self.http, &flow.http);
+
+        if flow.biflow == Biflow::Initiator {
+            self.biflow = Biflow::Initiator;
+        }
+    }
+
+    // Scale counters down to a coarser sample rate so flows sampled at
+    // different rates can be merged. Counts round to nearest, and a
+    // direction that saw packets keeps at least one. An unset rate of
+    // zero is unsampled.
+    pub fn resample(&mut self, sample: u32) {
+        let rate = self.sample.max(1);
+        if sample <= rate {
+            return;
+        }
+
+        let (num, den) = (rate as usize, sample as usize);
+        let scale = |n: usize| (n * num + den / 2) / den;
+        let count = |n: usize| max(scale(n), min(n, 1));
+
+        self.bytes           = scale(self.bytes);
+        self.packets         = count(self.packets);
+        self.reverse.bytes   = scale(self.reverse.bytes);
+        self.reverse.packets = count(self.reverse.packets);
+        self.sample          = sample;
+
+        for n in self.sizes.hist.iter_mut() {
+            *n = scale(*n as usize) as u32;
+        }
+    }
+
+    pub fn flip(&mut self) {
+        mem::swap(&mut self.src, &mut self.dst);
+        mem::swap(&mut self.ethernet.src, &mut self.ethernet.dst);
+        mem::swap(&mut self.bytes, &mut self.reverse.bytes);
+        mem::swap(&mut self.packets, &mut self.reverse.packets);
+
+        self.direction = match self.direction {
+            Direction::In      => Direction::Out,
+            Direction::Out     => Direction::In,
+            Direction::Transit => Direction::Transit,
+            Direction::Unknown => Direction::Unknown,
+        };
+    }
+
     pub fn tcp_flags(&self) -> u16 {
         match self.transport {
             Transport::TCP { flags, .. } => flags,
@@ -83,10 +221,90 @@ impl Flow {
     }
 }
 
+impl Key {
+    pub fn reverse(&self) -> Key {
+        Key(self.0, self.2, self.1)
+    }
+}
+
+impl Sizes {
+    pub fn record(&mut self, size: u32) {
+        self.min = match self.min {
+            0 => size,
+            n => min(n, size),
+        };
+        self.max = max(self.max, size);
+        self.hist[bucket(size)] += 1;
+    }
+
+    pub fn merge(&mut self, other: &Sizes) {
+        self.min = match (self.min, other.min) {
+            (0, n) | (n, 0) => n,
+            (a, b)          => min(a, b),
+        };
+        self.max = max(self.max, other.max);
+
+        for (n, m) in self.hist.iter_mut().zip(&other.hist) {
+            *n = n.saturating_add(*m);
+        }
+    }
+}
+
+// Packets below 128 bytes, then one bucket per power of two up to
+// 2048 bytes and everything larger.
+fn bucket(size: u32) -> usize {
+    let bits = (32 - size.leading_zeros()) as usize;
+    min(bits.saturating_sub(7), SIZE_BUCKETS - 1)
+}
+
+impl Rtt {
+    pub fn total(&self) -> Duration {
+        self.client + self.server
+    }
+}
 
 impl Default for Flow {
     fn default() -> Self {
-        unsafe { mem::zeroed() }
+        Flow {
+            timestamp:   Timestamp::default(),
+            start:       Timestamp::default(),
+            end:         Timestamp::default(),
+            closed:      false,
+            ethernet:    Ethernet::default(),
+            protocol:    Protocol::Other(0),
+            src:         Addr::default(),
+            dst:         Addr::default(),
+            tos:         0,
+            transport:   Transport::Other,
+            packets:     0,
+            fragments:   0,
+            retransmits: 0,
+            sizes:       Sizes::default(),
+            fragment:    Fragment::default(),
+            bytes:       0,
+            sample:      0,
+            hashed:      false,
+            direction:   Direction::Unknown,
+            index:       0,
+            tunnel:      None,
+            mpls:        Mpls::default(),
+            rtt:         Rtt::default(),
+            tls:         None,
+            http:        None,
+            app:         App::Unknown,
+            reverse:     Counter::default(),
+            biflow:      Biflow::Off,
+            dns:         None,
+        }
+    }
+}
+
+impl Default for Addr {
+    fn default() -> Self {
+        Addr {
+            addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+        }
     }
 }
 
@@ -105,6 +323,14 @@ impl fmt::Display for Addr {
     }
 }
 
+pub fn merge_http(a: &Option<Arc<Http>>, b: &Option<Arc<Http>>) -> Option<Arc<Http>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(Arc::new(a.merge(b))),
+        (a,       None)    => a.clone(),
+        (None,    b)       => b.clone(),
+    }
+}
+
 pub fn tcp_window(p: &TcpPacket) -> Window {
     let mut scale = 1u8;
 
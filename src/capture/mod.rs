@@ -1,22 +1,40 @@
 use std::time::Duration;
 use regex::Regex;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Config {
     pub capture:     Regex,
     pub exclude:     Regex,
+    pub xdp:         Regex,
+    pub mirror:      Regex,
+    pub packet:      Regex,
+    pub fanout:      usize,
     pub interval:    Duration,
+    pub timeouts:    Timeouts,
     pub buffer_size: u64,
     pub sample:      Sample,
+    pub filter:      Option<String>,
     pub snaplen:     u64,
+    pub inspect:     bool,
     pub promisc:     bool,
+    pub biflow:      bool,
+    pub profiles:    Vec<Profile>,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct Timeouts {
+    pub active: Duration,
+    pub idle:   Duration,
+}
+
+pub use app::App;
 pub use capture::capture;
-pub use decode::decode;
+pub use decode::{decode, Datalink, Parse};
 pub use flow::{Addr, Direction, Flow, Key, Protocol};
-pub use sample::{sample, Sample};
-pub use source::Sources;
+pub use profile::{profiles, Profile};
+pub use sample::{filter, sample, select, Adapt, Random, Sample};
+pub use source::{Link, Sources};
+pub use stats::{Snapshot, Stats};
 pub use self::time::Timestamp;
 
 pub mod flow;
@@ -24,10 +42,73 @@ pub mod queue;
 pub mod time;
 pub mod timer;
 
+mod app;
 mod capture;
 mod decode;
+mod profile;
 mod sample;
 mod source;
+mod stats;
+
+#[cfg(target_os = "linux")]
+mod ring;
+
+#[cfg(target_os = "linux")]
+mod xdp;
+
+#[cfg(not(target_os = "linux"))]
+mod ring {
+    use anyhow::{Result, anyhow};
+    use pcap::Packet;
+    use super::{Config, Datalink};
+
+    pub struct Ring;
+
+    impl Ring {
+        pub fn open(_dev: &str, _group: u16, _workers: usize, _cfg: &Config) -> Result<Self> {
+            Err(anyhow!("unsupported"))
+        }
+
+        pub fn poll<F: FnMut(Packet<'_>) -> Result<()>>(&mut self, _timeout: i32, _f: F) -> Result<bool> {
+            Err(anyhow!("unsupported"))
+        }
+
+        pub fn sample(&self, _rate: u32) -> Result<()> {
+            Err(anyhow!("unsupported"))
+        }
+
+        pub fn drops(&self) -> Result<u64> {
+            Err(anyhow!("unsupported"))
+        }
+
+        pub fn datalink(&self) -> Datalink {
+            Datalink::Ethernet
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod xdp {
+    use anyhow::{Result, anyhow};
+    use pcap::Packet;
+    use super::Config;
+
+    pub struct Xdp;
+
+    impl Xdp {
+        pub fn open(_name: &str, _dev: &str, _cfg: &Config) -> Result<Self> {
+            Err(anyhow!("unsupported"))
+        }
+
+        pub fn poll<F: FnMut(Packet<'_>) -> Result<()>>(&mut self, _timeout: i32, _f: F) -> Result<bool> {
+            Err(anyhow!("unsupported"))
+        }
+
+        pub fn drops(&self) -> Result<u64> {
+            Err(anyhow!("unsupported"))
+        }
+    }
+}
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,60 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use serde::Deserialize;
+use super::{Config, Sample};
+
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub pattern:     Regex,
+    pub sample:      Option<Sample>,
+    pub filter:      Option<String>,
+    pub snaplen:     Option<u64>,
+    pub promisc:     Option<bool>,
+    pub buffer_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    #[serde(rename = "match")]
+    pattern: String,
+    sample:  Option<String>,
+    filter:  Option<String>,
+    snaplen: Option<u64>,
+    promisc: Option<bool>,
+    buffer:  Option<u64>,
+}
+
+// Parse an ordered JSON list of interface profiles, the first profile
+// whose pattern matches a link name applies to it.
+pub fn profiles(json: &[u8]) -> Result<Vec<Profile>> {
+    let specs: Vec<Spec> = serde_json::from_slice(json)?;
+    specs.into_iter().map(|spec| {
+        let pattern = Regex::new(&spec.pattern)?;
+        let sample  = spec.sample.map(|s| s.parse::<Sample>()).transpose();
+        Ok(Profile {
+            sample:      sample.map_err(|e| anyhow!("profile '{}': {}", pattern, e))?,
+            pattern:     pattern,
+            filter:      spec.filter,
+            snaplen:     spec.snaplen,
+            promisc:     spec.promisc,
+            buffer_size: spec.buffer,
+        })
+    }).collect()
+}
+
+impl Config {
+    pub fn profile(&self, name: &str) -> Config {
+        let mut cfg = self.clone();
+
+        if let Some(p) = self.profiles.iter().find(|p| p.pattern.is_match(name)) {
+            cfg.sample      = p.sample.unwrap_or(cfg.sample);
+            cfg.filter      = p.filter.clone().or(cfg.filter);
+            cfg.snaplen     = p.snaplen.unwrap_or(cfg.snaplen);
+            cfg.promisc     = p.promisc.unwrap_or(cfg.promisc);
+            cfg.buffer_size = p.buffer_size.unwrap_or(cfg.buffer_size);
+        }
+
+        cfg
+    }
+}
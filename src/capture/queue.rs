@@ -1,18 +1,44 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
 use anyhow::Result;
 use crossbeam_channel::Sender;
 use log::warn;
 use pcap::Packet;
 use pnet::util::MacAddr;
 use time::Duration;
-use super::{decode, Timestamp, timer::Timer};
-use super::flow::{Flow, Key};
+use super::{app, decode, select, App, Datalink, Parse, Stats, Timestamp, timer::Timer};
+use super::flow::{Biflow, Direction, Flow, Key, Rtt, Transport, ACK, FIN, RST, SYN};
+use crate::dns::Cache;
+use crate::link::Locals;
+use crate::packet::Fragment;
 use crossbeam_channel::TrySendError::*;
 
+#[derive(Copy, Clone)]
+struct Handshake {
+    syn:    Timestamp,
+    synack: Option<Timestamp>,
+}
+
 pub struct Queue {
     queue:  HashMap<Key, Flow>,
+    frags:  HashMap<(IpAddr, IpAddr, u32), (Key, Timestamp)>,
+    seqs:   HashMap<Key, (u32, Timestamp)>,
+    shakes: HashMap<Key, Handshake>,
+    link:   Datalink,
     mac:    Option<MacAddr>,
+    index:  u32,
     sample: u32,
+    hashed: bool,
+    biflow: bool,
+    parse:  bool,
+    whole:  bool,
+    active: Duration,
+    idle:   Duration,
+    dns:    Option<Arc<Cache>>,
+    locals: Option<Locals>,
+    stats:  Arc<Stats>,
+    root:   bool,
     timer:  Timer,
     tx:     Sender<Vec<Flow>>,
     done:   bool,
@@ -22,47 +48,424 @@ impl Queue {
     pub fn new(mac: Option<MacAddr>, sample: u32, tx: Sender<Vec<Flow>>, interval: Duration) -> Self {
         Self {
             queue:  HashMap::new(),
+            frags:  HashMap::new(),
+            seqs:   HashMap::new(),
+            shakes: HashMap::new(),
+            link:   Datalink::Ethernet,
             mac:    mac,
+            index:  0,
             sample: sample,
+            hashed: false,
+            biflow: false,
+            parse:  false,
+            whole:  true,
+            active: Duration::seconds(60),
+            idle:   Duration::seconds(15),
+            dns:    None,
+            locals: None,
+            stats:  Arc::new(Stats::default()),
+            root:   true,
             timer:  Timer::new(interval),
             tx:     tx,
             done:   false,
         }
     }
 
+    pub fn fork(&self, tx: Sender<Vec<Flow>>) -> Self {
+        Self {
+            queue:  HashMap::new(),
+            frags:  HashMap::new(),
+            seqs:   HashMap::new(),
+            shakes: HashMap::new(),
+            link:   self.link,
+            mac:    self.mac,
+            index:  self.index,
+            sample: self.sample,
+            hashed: self.hashed,
+            biflow: self.biflow,
+            parse:  self.parse,
+            whole:  self.whole,
+            active: self.active,
+            idle:   self.idle,
+            dns:    self.dns.clone(),
+            locals: self.locals.clone(),
+            stats:  self.stats.clone(),
+            root:   false,
+            timer:  self.timer.clone(),
+            tx:     tx,
+            done:   false,
+        }
+    }
+
+    pub fn set_datalink(&mut self, link: Datalink) {
+        self.link = link;
+    }
+
+    pub fn set_index(&mut self, index: u32) {
+        self.index = index;
+    }
+
+    pub fn set_hashed(&mut self, hashed: bool) {
+        self.hashed = hashed;
+    }
+
+    pub fn set_biflow(&mut self, biflow: bool) {
+        self.biflow = biflow;
+    }
+
+    pub fn set_parse(&mut self, parse: bool) {
+        self.parse = parse;
+    }
+
+    // Payloads truncated to the snaplen are only classified, neither
+    // inspected nor used to learn DNS names.
+    pub fn set_whole(&mut self, whole: bool) {
+        self.whole = whole;
+    }
+
+    pub fn set_timeouts(&mut self, active: Duration, idle: Duration) {
+        self.active = active;
+        self.idle   = idle;
+    }
+
+    // Flows recorded at the previous rate are exported first so each
+    // carries the rate its packets were actually sampled at.
+    pub fn set_sample(&mut self, sample: u32) {
+        if sample != self.sample {
+            self.flush();
+            self.sample = sample;
+        }
+    }
+
+    pub fn set_dns(&mut self, dns: Arc<Cache>) {
+        self.dns = Some(dns);
+    }
+
+    pub fn set_locals(&mut self, locals: Locals) {
+        self.locals = Some(locals);
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
     pub fn record(&mut self, pkt: Packet<'_>) -> Result<()> {
-        if let Some(mut flow) = decode(self.mac, pkt) {
+        let flow = decode(self.link, self.mac, pkt, |flow| self.inspect(flow));
+        self.stats.packet(flow.is_some());
+
+        if let Some(mut flow) = flow {
+            flow.index  = self.index;
             flow.sample = self.sample;
+            flow.hashed = self.hashed;
+            flow.closed = flow.tcp_flags() & (FIN | RST) != 0;
+            flow.sizes.record(flow.bytes as u32);
+
+            if let Some(dir) = self.direction(&flow) {
+                flow.direction = dir;
+            }
 
-            let ts  = flow.timestamp;
-            let key = flow.key();
+            if let (Some(r), Some(dns), true) = (flow.dns.take(), &self.dns, self.whole) {
+                dns.record(*r);
+            }
 
-            self.queue.entry(key).and_modify(|entry| {
-                entry.bytes   += flow.bytes;
-                entry.packets += 1;
-                entry.tos     |= flow.tos;
-            }).or_insert(flow);
+            if flow.fragments > 0 {
+                self.fragment(&mut flow);
+            }
+
+            if self.hashed && !select(&flow.key(), self.sample) {
+                return Ok(());
+            }
+
+            self.sequence(&mut flow);
+            self.handshake(&mut flow);
+
+            let ts = flow.timestamp;
+
+            match self.biflow {
+                true  => self.bidirectional(flow),
+                false => self.unidirectional(flow),
+            }
 
             self.export(ts);
         }
         Ok(())
     }
 
-    pub fn export(&mut self, ts: Timestamp) {
-        if self.timer.ready(ts) && self.queue.len() > 0 {
-            let flows = self.queue.drain().map(|(_, flow)| {
-                flow
-            }).collect();
-
-            match self.tx.try_send(flows) {
-                Ok(_)                => (),
-                Err(Full(_))         => warn!("capture channel full"),
-                Err(Disconnected(_)) => self.done = true,
+    fn unidirectional(&mut self, flow: Flow) {
+        self.queue.entry(flow.key()).and_modify(|entry| {
+            entry.merge(&flow);
+        }).or_insert(flow);
+    }
+
+    // Payloads are classified within the first packets of a flow until
+    // its application is known, and only inspected further when enabled
+    // and captured whole.
+    fn inspect(&self, flow: &Flow) -> Parse {
+        let key   = flow.key();
+        let entry = match self.biflow {
+            true  => self.queue.get(&key).or_else(|| self.queue.get(&key.reverse())),
+            false => self.queue.get(&key),
+        };
+
+        let unknown = entry.map_or(true, |entry| {
+            entry.app == App::Unknown && entry.packets + entry.reverse.packets < PARSE_PACKETS
+        });
+
+        match (unknown, self.parse && self.whole) {
+            (true,  true)  => Parse::Inspect,
+            (true,  false) => Parse::Classify,
+            (false, _)     => Parse::None,
+        }
+    }
+
+    // Classify by which endpoints are local to the namespace of the link,
+    // keeping the link layer direction when both or neither are known, or
+    // the destination is not a unicast address beyond the link.
+    fn direction(&self, flow: &Flow) -> Option<Direction> {
+        let locals = self.locals.as_ref()?;
+        if scoped(&flow.dst.addr) {
+            return None;
+        }
+
+        match locals.lookup(&flow.src.addr, &flow.dst.addr)? {
+            (true,  false) => Some(Direction::Out),
+            (false, true)  => Some(Direction::In),
+            (false, false) => Some(Direction::Transit),
+            (true,  true)  => None,
+        }
+    }
+
+    // Non-first fragments carry no transport header, attribute them to
+    // the flow of the first fragment with the same (src, dst, id).
+    fn fragment(&mut self, flow: &mut Flow) {
+        let Fragment { id, offset, more } = flow.fragment;
+        let ids = (flow.src.addr, flow.dst.addr, id);
+
+        if offset == 0 {
+            self.frags.insert(ids, (flow.key(), flow.timestamp));
+            return;
+        }
+
+        if let Some(&(Key(protocol, src, dst), _)) = self.frags.get(&ids) {
+            flow.protocol = protocol;
+            flow.src      = src;
+            flow.dst      = dst;
+
+            if !more {
+                self.frags.remove(&ids);
             }
         }
     }
 
+    // Count segments carrying data below the highest sequence number
+    // seen so far as retransmitted or out of order.
+    fn sequence(&mut self, flow: &mut Flow) {
+        let (seq, flags, len) = match flow.transport {
+            Transport::TCP { seq, flags, len, .. } => (seq, flags, len),
+            _                                      => return,
+        };
+
+        let mut end = seq.wrapping_add(len);
+        if flags & (SYN | FIN) != 0 {
+            end = end.wrapping_add(1);
+        }
+
+        let ts = flow.timestamp;
+
+        match self.seqs.get_mut(&flow.key()) {
+            Some((next, seen)) => {
+                if len > 0 && before(seq, *next) {
+                    flow.retransmits = 1;
+                }
+
+                if before(*next, end) {
+                    *next = end;
+                }
+
+                *seen = ts;
+            },
+            None => {
+                self.seqs.insert(flow.key(), (end, ts));
+            }
+        }
+    }
+
+    // Time SYN -> SYN-ACK (server side) and SYN-ACK -> ACK (client side)
+    // as seen from the capture point.
+    fn handshake(&mut self, flow: &mut Flow) {
+        let flags = match flow.transport {
+            Transport::TCP { flags, .. } => flags,
+            _                            => return,
+        };
+
+        let ts  = flow.timestamp;
+        let key = flow.key();
+
+        match flags & (SYN | ACK) {
+            SYN => {
+                self.shakes.insert(key, Handshake {
+                    syn:    ts,
+                    synack: None,
+                });
+            },
+            f if f == SYN | ACK => {
+                let key = key.reverse();
+                if let Some(shake) = self.shakes.get_mut(&key) {
+                    shake.synack = shake.synack.or(Some(ts));
+                }
+            },
+            ACK => {
+                if let Some(&Handshake { syn, synack: Some(synack) }) = self.shakes.get(&key) {
+                    self.shakes.remove(&key);
+
+                    let rtt = Rtt {
+                        client: (ts - synack).to_std().unwrap_or_default(),
+                        server: (synack - syn).to_std().unwrap_or_default(),
+                    };
+
+                    if let Some(entry) = self.queue.get_mut(&key.reverse()) {
+                        entry.rtt = rtt;
+                    }
+
+                    flow.rtt = rtt;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // Orient a new biflow from the initiator when the handshake reveals
+    // it, otherwise canonically, and merge it with any existing record
+    // for either direction.
+    fn bidirectional(&mut self, mut flow: Flow) {
+        if flow.biflow == Biflow::Off {
+            flow.biflow = match flow.tcp_flags() & (SYN | ACK) {
+                SYN               => Biflow::Initiator,
+                f if f == SYN|ACK => {
+                    flow.flip();
+                    Biflow::Initiator
+                },
+                _                 => {
+                    if flow.src > flow.dst {
+                        flow.flip();
+                    }
+                    Biflow::Canonical
+                },
+            };
+        }
+
+        let key = flow.key();
+
+        if let Some(entry) = self.queue.get_mut(&key) {
+            entry.merge(&flow);
+            return;
+        }
+
+        match self.queue.remove(&key.reverse()) {
+            Some(mut entry) if entry.biflow == Biflow::Initiator || flow.biflow == Biflow::Canonical => {
+                flow.flip();
+                entry.merge(&flow);
+                self.queue.insert(entry.key(), entry);
+            },
+            Some(mut entry) => {
+                entry.flip();
+                entry.merge(&flow);
+                self.queue.insert(key, entry);
+            },
+            None => {
+                self.queue.insert(key, flow);
+            },
+        }
+    }
+
+    pub fn merge(&mut self, flows: Vec<Flow>) {
+        if self.biflow {
+            flows.into_iter().for_each(|flow| self.bidirectional(flow));
+            return;
+        }
+
+        for flow in flows {
+            self.queue.entry(flow.key()).and_modify(|entry| {
+                entry.merge(&flow);
+            }).or_insert(flow);
+        }
+    }
+
+    pub fn export(&mut self, ts: Timestamp) {
+        if self.timer.ready(ts) {
+            let timeout = Duration::seconds(30);
+            self.frags.retain(|_, (_, seen)| ts - *seen < timeout);
+            self.shakes.retain(|_, shake| ts - shake.syn < timeout);
+
+            let timeout = Duration::seconds(60);
+            self.seqs.retain(|_, (_, seen)| ts - *seen < timeout);
+            self.expire(ts);
+        }
+    }
+
+    // Export flows closed by FIN or RST, idle for longer than the idle
+    // timeout, or active for longer than the active timeout. Everything
+    // else stays queued and keeps accumulating.
+    fn expire(&mut self, ts: Timestamp) {
+        let (active, idle) = (self.active, self.idle);
+
+        let keys = self.queue.iter().filter(|(_, flow)| {
+            flow.closed || ts - flow.end >= idle || ts - flow.start >= active
+        }).map(|(key, _)| *key).collect::<Vec<_>>();
+
+        let flows = keys.iter().filter_map(|key| {
+            self.queue.remove(key)
+        }).collect();
+
+        self.send(flows);
+    }
+
+    pub fn flush(&mut self) {
+        let flows = self.queue.drain().map(|(_, flow)| {
+            flow
+        }).collect();
+
+        self.send(flows);
+    }
+
+    fn send(&mut self, mut flows: Vec<Flow>) {
+        if flows.is_empty() {
+            return;
+        }
+
+        for flow in flows.iter_mut().filter(|flow| flow.app == App::Unknown) {
+            flow.app = app::port(flow.protocol, flow.src, flow.dst);
+        }
+
+        // Forked queues feed their parent, which counts emitted flows.
+        let n = flows.len() as u64;
+        match self.tx.try_send(flows) {
+            Ok(_) if self.root   => self.stats.flows(n),
+            Ok(_)                => (),
+            Err(Full(_))         => {
+                warn!("capture channel full");
+                self.stats.channel(n);
+            },
+            Err(Disconnected(_)) => self.done = true,
+        }
+    }
+
     pub fn done(&self) -> bool {
         self.done
     }
 }
+
+// Multicast, broadcast and link-local addresses are never local to the
+// namespace so they say nothing of the direction.
+fn scoped(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_multicast() || ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+const PARSE_PACKETS: usize = 8;
@@ -0,0 +1,351 @@
+use std::cmp::{max, min};
+use std::io::{Error, ErrorKind};
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{fence, Ordering};
+use anyhow::{Result, anyhow};
+use libc::{pollfd, sockaddr_ll, socklen_t, timeval, POLLIN};
+use pcap::{Packet, PacketHeader};
+use crate::os::ifindex;
+use super::{Config, Datalink, filter};
+use super::sample::FULL_SNAPLEN;
+
+const BLOCK_SIZE: u32 = 1 << 20;
+const FRAME_SIZE: u32 = 1 << 11;
+const RETIRE_TOV: u32 = 100;
+
+pub struct Ring {
+    fd:      c_int,
+    map:     *mut u8,
+    blocks:  u32,
+    next:    u32,
+    snaplen: u32,
+    link:    Datalink,
+    cfg:     Config,
+    frame:   Vec<u8>,
+}
+
+unsafe impl Send for Ring {}
+
+impl Ring {
+    pub fn open(dev: &str, group: u16, workers: usize, cfg: &Config) -> Result<Self> {
+        let index  = ifindex(dev)?;
+        let blocks = cfg.buffer_size / workers as u64 / BLOCK_SIZE as u64;
+        let blocks = max(blocks as u32, 4);
+
+        let fd = check(unsafe {
+            libc::socket(libc::AF_PACKET, libc::SOCK_RAW, (ETH_P_ALL as u16).to_be() as c_int)
+        })?;
+
+        let mut ring = Self {
+            fd:      fd,
+            map:     ptr::null_mut(),
+            blocks:  blocks,
+            next:    0,
+            snaplen: max(cfg.snaplen, FULL_SNAPLEN) as u32,
+            link:    Datalink::Ethernet,
+            cfg:     cfg.clone(),
+            frame:   Vec::new(),
+        };
+
+        setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+        setsockopt(fd, PACKET_RX_RING, &tpacket_req3 {
+            tp_block_size:       BLOCK_SIZE,
+            tp_block_nr:         blocks,
+            tp_frame_size:       FRAME_SIZE,
+            tp_frame_nr:         BLOCK_SIZE / FRAME_SIZE * blocks,
+            tp_retire_blk_tov:   RETIRE_TOV,
+            tp_sizeof_priv:      0,
+            tp_feature_req_word: 0,
+        })?;
+
+        ring.map = unsafe {
+            let size  = BLOCK_SIZE as usize * blocks as usize;
+            let prot  = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_SHARED | libc::MAP_LOCKED;
+            match libc::mmap(ptr::null_mut(), size, prot, flags, fd, 0) {
+                libc::MAP_FAILED => return Err(Error::last_os_error().into()),
+                ptr              => ptr as *mut u8,
+            }
+        };
+
+        let mut sa: sockaddr_ll = unsafe { std::mem::zeroed() };
+        sa.sll_family   = libc::AF_PACKET as u16;
+        sa.sll_protocol = (ETH_P_ALL as u16).to_be();
+        sa.sll_ifindex  = index as c_int;
+
+        check(unsafe {
+            let ptr = &sa as *const _ as *const libc::sockaddr;
+            let len = size_of::<sockaddr_ll>() as socklen_t;
+            libc::bind(fd, ptr, len)
+        })?;
+
+        ring.link = datalink(fd)?;
+        ring.sample(cfg.sample.kernel())?;
+
+        if cfg.promisc {
+            setsockopt(fd, PACKET_ADD_MEMBERSHIP, &packet_mreq {
+                mr_ifindex: index as c_int,
+                mr_type:    PACKET_MR_PROMISC,
+                mr_alen:    0,
+                mr_address: [0; 8],
+            })?;
+        }
+
+        let fanout = group as u32 | PACKET_FANOUT_HASH << 16;
+        setsockopt(fd, PACKET_FANOUT, &fanout)?;
+
+        Ok(ring)
+    }
+
+    pub fn poll<F: FnMut(Packet<'_>) -> Result<()>>(&mut self, timeout: i32, mut f: F) -> Result<bool> {
+        let block = unsafe { &mut *self.block(self.next) };
+
+        if block.block_status & TP_STATUS_USER == 0 {
+            let mut fds = pollfd {
+                fd:      self.fd,
+                events:  POLLIN,
+                revents: 0,
+            };
+
+            match unsafe { libc::poll(&mut fds, 1, timeout) } {
+                0  => return Ok(false),
+                -1 => return match Error::last_os_error() {
+                    e if e.kind() == ErrorKind::Interrupted => Ok(false),
+                    e                                       => Err(e.into()),
+                },
+                _  => (),
+            }
+
+            fence(Ordering::Acquire);
+
+            if block.block_status & TP_STATUS_USER == 0 {
+                return Ok(true);
+            }
+        }
+
+        fence(Ordering::Acquire);
+
+        let base = block as *mut tpacket_block_desc as *const u8;
+        let mut offset = block.offset_to_first_pkt as usize;
+
+        for _ in 0..block.num_pkts {
+            let hdr = unsafe { &*(base.add(offset) as *const tpacket3_hdr) };
+
+            let caplen = min(hdr.tp_snaplen, self.snaplen);
+            let data   = unsafe {
+                let ptr = base.add(offset + hdr.tp_mac as usize);
+                slice::from_raw_parts(ptr, caplen as usize)
+            };
+
+            let vlan = hdr.tp_status & TP_STATUS_VLAN_VALID != 0;
+            let (data, extra) = match self.link {
+                Datalink::Ethernet if vlan => (self.tag(hdr, data), 4),
+                _                          => (data, 0),
+            };
+
+            let header = PacketHeader {
+                ts: timeval {
+                    tv_sec:  hdr.tp_sec            as _,
+                    tv_usec: (hdr.tp_nsec / 1000)  as _,
+                },
+                caplen: caplen    + extra,
+                len:    hdr.tp_len + extra,
+            };
+
+            f(Packet::new(&header, data))?;
+
+            offset += hdr.tp_next_offset as usize;
+        }
+
+        fence(Ordering::Release);
+        block.block_status = TP_STATUS_KERNEL;
+
+        self.next = (self.next + 1) % self.blocks;
+
+        Ok(true)
+    }
+
+    pub fn sample(&self, rate: u32) -> Result<()> {
+        filter(self.fd, rate, &self.cfg, self.link.linktype())
+    }
+
+    pub fn datalink(&self) -> Datalink {
+        self.link
+    }
+
+    // Packets dropped by the kernel since the previous call, reading the
+    // statistics resets them.
+    pub fn drops(&self) -> Result<u64> {
+        let mut stats = tpacket_stats_v3::default();
+        getsockopt(self.fd, PACKET_STATISTICS, &mut stats)?;
+        Ok(stats.tp_drops as u64)
+    }
+
+    // The kernel strips the outermost 802.1Q tag into the frame header,
+    // reinsert it so decoding matches libpcap captures.
+    fn tag<'a>(&'a mut self, hdr: &tpacket3_hdr, data: &[u8]) -> &'a [u8] {
+        let tpid = match hdr.tp_status & TP_STATUS_VLAN_TPID_VALID {
+            0 => 0x8100,
+            _ => hdr.tp_vlan_tpid,
+        };
+        let tci = hdr.tp_vlan_tci as u16;
+
+        let n = min(data.len(), 12);
+        self.frame.clear();
+        self.frame.extend_from_slice(&data[..n]);
+        self.frame.extend_from_slice(&tpid.to_be_bytes());
+        self.frame.extend_from_slice(&tci.to_be_bytes());
+        self.frame.extend_from_slice(&data[n..]);
+        &self.frame
+    }
+
+    fn block(&self, n: u32) -> *mut tpacket_block_desc {
+        unsafe {
+            let offset = n as usize * BLOCK_SIZE as usize;
+            self.map.add(offset) as *mut tpacket_block_desc
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.map.is_null() {
+                let size = BLOCK_SIZE as usize * self.blocks as usize;
+                libc::munmap(self.map as *mut c_void, size);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn check(rc: c_int) -> Result<c_int> {
+    match rc {
+        -1 => Err(Error::last_os_error().into()),
+        rc => Ok(rc),
+    }
+}
+
+// SOCK_RAW sockets deliver frames starting at the link header, which is
+// absent on tunnel and raw IP devices.
+fn datalink(fd: c_int) -> Result<Datalink> {
+    let mut sa: sockaddr_ll = unsafe { std::mem::zeroed() };
+    check(unsafe {
+        let ptr = &mut sa as *mut _ as *mut libc::sockaddr;
+        let mut len = size_of::<sockaddr_ll>() as socklen_t;
+        libc::getsockname(fd, ptr, &mut len)
+    })?;
+
+    match sa.sll_hatype {
+        ARPHRD_ETHER | ARPHRD_LOOPBACK => Ok(Datalink::Ethernet),
+        ARPHRD_RAWIP | ARPHRD_TUNNEL   => Ok(Datalink::Raw),
+        ARPHRD_TUNNEL6 | ARPHRD_SIT    => Ok(Datalink::Raw),
+        ARPHRD_IPGRE | ARPHRD_NONE     => Ok(Datalink::Raw),
+        hatype                         => Err(anyhow!("unsupported link type {}", hatype)),
+    }
+}
+
+fn setsockopt<T>(fd: c_int, name: c_int, val: &T) -> Result<()> {
+    check(unsafe {
+        let val = val as *const T as *const c_void;
+        let len = size_of::<T>() as socklen_t;
+        libc::setsockopt(fd, SOL_PACKET, name, val, len)
+    })?;
+    Ok(())
+}
+
+fn getsockopt<T>(fd: c_int, name: c_int, val: &mut T) -> Result<()> {
+    check(unsafe {
+        let mut len = size_of::<T>() as socklen_t;
+        let val = val as *mut T as *mut c_void;
+        libc::getsockopt(fd, SOL_PACKET, name, val, &mut len)
+    })?;
+    Ok(())
+}
+
+const ETH_P_ALL: c_int = 0x0003;
+const SOL_PACKET: c_int = 263;
+
+const PACKET_ADD_MEMBERSHIP: c_int = 1;
+const PACKET_RX_RING:        c_int = 5;
+const PACKET_STATISTICS:     c_int = 6;
+const PACKET_VERSION:        c_int = 10;
+const PACKET_FANOUT:         c_int = 18;
+
+const PACKET_MR_PROMISC:  u16 = 1;
+const PACKET_FANOUT_HASH: u32 = 0;
+
+const TPACKET_V3: c_int = 2;
+
+const ARPHRD_ETHER:    u16 = 1;
+const ARPHRD_RAWIP:    u16 = 519;
+const ARPHRD_TUNNEL:   u16 = 768;
+const ARPHRD_TUNNEL6:  u16 = 769;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ARPHRD_SIT:      u16 = 776;
+const ARPHRD_IPGRE:    u16 = 778;
+const ARPHRD_NONE:     u16 = 65534;
+
+const TP_STATUS_KERNEL:         u32 = 0;
+const TP_STATUS_USER:           u32 = 1 << 0;
+const TP_STATUS_VLAN_VALID:     u32 = 1 << 4;
+const TP_STATUS_VLAN_TPID_VALID: u32 = 1 << 6;
+
+#[repr(C)]
+struct tpacket_req3 {
+    tp_block_size:       u32,
+    tp_block_nr:         u32,
+    tp_frame_size:       u32,
+    tp_frame_nr:         u32,
+    tp_retire_blk_tov:   u32,
+    tp_sizeof_priv:      u32,
+    tp_feature_req_word: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct tpacket_stats_v3 {
+    tp_packets:      u32,
+    tp_drops:        u32,
+    tp_freeze_q_cnt: u32,
+}
+
+#[repr(C)]
+struct packet_mreq {
+    mr_ifindex: c_int,
+    mr_type:    u16,
+    mr_alen:    u16,
+    mr_address: [u8; 8],
+}
+
+#[repr(C)]
+struct tpacket_block_desc {
+    version:             u32,
+    offset_to_priv:      u32,
+    block_status:        u32,
+    num_pkts:            u32,
+    offset_to_first_pkt: u32,
+    blk_len:             u32,
+    seq_num:             u64,
+    ts_first_pkt:        [u32; 2],
+    ts_last_pkt:         [u32; 2],
+}
+
+#[repr(C)]
+struct tpacket3_hdr {
+    tp_next_offset: u32,
+    tp_sec:         u32,
+    tp_nsec:        u32,
+    tp_snaplen:     u32,
+    tp_len:         u32,
+    tp_status:      u32,
+    tp_mac:         u16,
+    tp_net:         u16,
+    tp_rxhash:      u32,
+    tp_vlan_tci:    u32,
+    tp_vlan_tpid:   u16,
+    tp_padding:     u16,
+}
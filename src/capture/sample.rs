@@ -1,29 +1,282 @@
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 use anyhow::Result;
 use pcap::{Capture, Active};
+use super::Config;
+use super::flow::Key;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub enum Sample {
     Rate(u32),
+    Hash(u32),
+    Adaptive(u32),
     None,
 }
 
+pub struct Adapt {
+    budget: u64,
+    rate:   u32,
+    count:  u64,
+    since:  Instant,
+    period: Duration,
+}
+
+// Random 1:N packet sampler for sources without a socket filter, such
+// as replayed capture files.
+pub struct Random {
+    rate:  u32,
+    state: u64,
+}
+
+impl Sample {
+    pub fn rate(&self) -> u32 {
+        match self {
+            Sample::Rate(n)     => *n,
+            Sample::Hash(n)     => *n,
+            Sample::Adaptive(_) => 1,
+            Sample::None        => 1,
+        }
+    }
+
+    // Rate applied by the socket filter, flow hash sampling happens in
+    // userspace once packets are decoded.
+    pub fn kernel(&self) -> u32 {
+        match self {
+            Sample::Rate(n) => *n,
+            _               => 1,
+        }
+    }
+}
+
+impl Adapt {
+    pub fn new(budget: u32, period: Duration) -> Self {
+        Self {
+            budget: budget.max(1) as u64,
+            rate:   1,
+            count:  0,
+            since:  Instant::now(),
+            period: period,
+        }
+    }
+
+    pub fn record(&mut self) -> Option<u32> {
+        self.count += 1;
+        match self.count % CHECK {
+            0 => self.update(),
+            _ => None,
+        }
+    }
+
+    // Estimate the unsampled packet rate from the sampled count and pick
+    // the lowest rate that keeps sampled packets under budget. Only relax
+    // the rate once traffic falls well below budget to avoid flapping.
+    pub fn update(&mut self) -> Option<u32> {
+        let elapsed = self.since.elapsed();
+        if elapsed < self.period {
+            return None;
+        }
+
+        let pps  = self.count as f64 * self.rate as f64 / elapsed.as_secs_f64();
+        let want = (pps / self.budget as f64).ceil().max(1.0) as u32;
+
+        self.count = 0;
+        self.since = Instant::now();
+
+        match want {
+            n if n > self.rate     => self.rate = n,
+            n if n * 2 <= self.rate => self.rate = n,
+            _                      => return None,
+        }
+
+        Some(self.rate)
+    }
+}
+
+impl Random {
+    pub fn new(rate: u32) -> Self {
+        let now  = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        let seed = now.map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Self {
+            rate:  rate,
+            state: seed | 1,
+        }
+    }
+
+    pub fn keep(&mut self) -> bool {
+        if self.rate <= 1 {
+            return true;
+        }
+
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+
+        let n = self.state.wrapping_mul(XORSHIFT_MUL) >> 32;
+        n % self.rate as u64 == 0
+    }
+}
+
+// Select 1:N flows by a hash of the canonical 5-tuple, so every agent
+// observing a connection selects the same flows in both directions.
+pub fn select(key: &Key, rate: u32) -> bool {
+    if rate <= 1 {
+        return true;
+    }
+
+    let Key(protocol, src, dst) = *key;
+    let (a, b) = match src < dst {
+        true  => (src, dst),
+        false => (dst, src),
+    };
+
+    let mut hash = FNV_OFFSET;
+    let mut fnv  = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    };
+
+    fnv(&u16::from(protocol).to_be_bytes());
+    for addr in &[a, b] {
+        match addr.addr {
+            IpAddr::V4(ip) => fnv(&ip.octets()),
+            IpAddr::V6(ip) => fnv(&ip.octets()),
+        }
+        fnv(&addr.port.to_be_bytes());
+    }
+
+    hash % rate as u64 == 0
+}
+
 #[cfg(target_os = "linux")]
-pub fn sample(cap: &Capture<Active>, rate: u32) -> Result<()> {
+pub fn sample(cap: &Capture<Active>, rate: u32, cfg: &Config) -> Result<()> {
     use std::os::unix::io::AsRawFd;
-    use bpf::{Op, Prog};
+    filter(cap.as_raw_fd(), rate, cfg, cap.get_datalink().0)
+}
+
+#[cfg(target_os = "linux")]
+pub fn filter(fd: RawFd, rate: u32, cfg: &Config, linktype: i32) -> Result<()> {
+    let ops = program(rate, cfg, linktype)?;
+    Ok(bpf::attach_filter(fd, bpf::Prog::new(ops))?)
+}
+
+// Build a socket filter that applies the configured filter expression,
+// samples 1:N of the matching packets, and truncates them to the snaplen
+// unless they match the FULL, or with inspection enabled INSPECT,
+// expression.
+#[cfg(target_os = "linux")]
+pub fn program(rate: u32, cfg: &Config, linktype: i32) -> Result<Vec<bpf::Op>> {
+    use bpf::Op;
+
+    let mut ops = match cfg.filter.as_deref() {
+        Some(expr) => compile(expr, linktype)?,
+        None       => Vec::new(),
+    };
+
+    // Send each accepting return of the filter expression to the sampling
+    // tail, so the rate applies to matching packets only.
+    let len = ops.len();
+    for (n, op) in ops.iter_mut().enumerate() {
+        if op.code == BPF_RET_K && op.k != 0 {
+            *op = Op::new(BPF_JA, 0, 0, (len - n - 1) as u32);
+        }
+    }
+
+    if rate > 1 {
+        ops.extend(vec![
+            Op::new(0x20, 0, 0, 0xfffff038),
+            Op::new(0x94, 0, 0, rate),
+            Op::new(0x15, 0, 1, 0x00000001),
+            Op::new(BPF_JA, 0, 0, 1),
+            Op::new(BPF_RET_K, 0, 0, 0),
+        ]);
+    }
+
+    // Sampled packets end in the snap tail, which keeps all of a packet
+    // matching the expression and truncates the rest.
+    let full = match cfg.inspect {
+        true  => INSPECT,
+        false => FULL,
+    };
+
+    let mut snap = compile(full, linktype)?;
+    for op in snap.iter_mut().filter(|op| op.code == BPF_RET_K) {
+        op.k = match op.k {
+            0 => cfg.snaplen as u32,
+            _ => 0xffffffff,
+        };
+    }
+    ops.extend(snap);
 
-    Ok(bpf::attach_filter(cap.as_raw_fd(), Prog::new(vec![
-        Op::new(0x20, 0, 0, 0xfffff038),
-        Op::new(0x94, 0, 0, rate),
-        Op::new(0x15, 0, 1, 0x00000001),
-        Op::new(0x06, 0, 0, 0xffffffff),
-        Op::new(0x06, 0, 0, 0000000000),
-    ]))?)
+    Ok(ops)
+}
+
+#[cfg(target_os = "linux")]
+fn compile(expr: &str, linktype: i32) -> Result<Vec<bpf::Op>> {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_int, c_uint, c_void};
+    use anyhow::anyhow;
+
+    #[repr(C)]
+    struct bpf_program {
+        bf_len:   c_uint,
+        bf_insns: *mut bpf_insn,
+    }
+
+    #[repr(C)]
+    struct bpf_insn {
+        code: u16,
+        jt:   u8,
+        jf:   u8,
+        k:    u32,
+    }
+
+    extern "C" {
+        fn pcap_open_dead(linktype: c_int, snaplen: c_int) -> *mut c_void;
+        fn pcap_compile(p: *mut c_void, prog: *mut bpf_program, expr: *const c_char, optimize: c_int, netmask: u32) -> c_int;
+        fn pcap_geterr(p: *mut c_void) -> *mut c_char;
+        fn pcap_freecode(prog: *mut bpf_program);
+        fn pcap_close(p: *mut c_void);
+    }
+
+    let cstr = CString::new(expr)?;
+
+    unsafe {
+        let pcap = pcap_open_dead(linktype, MAX_SNAPLEN);
+        if pcap.is_null() {
+            return Err(anyhow!("pcap_open_dead failed"));
+        }
+
+        let mut prog = bpf_program {
+            bf_len:   0,
+            bf_insns: std::ptr::null_mut(),
+        };
+
+        if pcap_compile(pcap, &mut prog, cstr.as_ptr(), 1, PCAP_NETMASK_UNKNOWN) != 0 {
+            let err = CStr::from_ptr(pcap_geterr(pcap)).to_string_lossy().into_owned();
+            pcap_close(pcap);
+            return Err(anyhow!("invalid filter '{}': {}", expr, err));
+        }
+
+        let insns = std::slice::from_raw_parts(prog.bf_insns, prog.bf_len as usize);
+        let ops   = insns.iter().map(|i| bpf::Op::new(i.code, i.jt, i.jf, i.k)).collect();
+
+        pcap_freecode(&mut prog);
+        pcap_close(pcap);
+
+        Ok(ops)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample(_cap: &Capture<Active>, _rate: u32, _cfg: &Config) -> Result<()> {
+    Err(anyhow::anyhow!("unsupported"))
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn sample(_cap: &Capture<Active>, _rate: u32) -> Result<()> {
+pub fn filter(_fd: RawFd, _rate: u32, _cfg: &Config, _linktype: i32) -> Result<()> {
     Err(anyhow::anyhow!("unsupported"))
 }
 
@@ -31,6 +284,20 @@ impl FromStr for Sample {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rate) = s.strip_prefix("hash:") {
+            return match u32::from_str(rate) {
+                Ok(n) if n > 0 => Ok(Sample::Hash(n)),
+                _              => Err(format!("invalid rate: {}", s)),
+            };
+        }
+
+        if let Some(budget) = s.strip_prefix("auto:") {
+            return match u32::from_str(budget) {
+                Ok(n) if n > 0 => Ok(Sample::Adaptive(n)),
+                _              => Err(format!("invalid budget: {}", s)),
+            };
+        }
+
         let mut split = s.split(':');
         let base = split.next().map(u32::from_str);
         let rate = split.next().map(u32::from_str);
@@ -39,9 +306,30 @@ impl FromStr for Sample {
         let rate = rate.ok_or_else(|| format!("missing rate: {}", s))?;
 
         match (base, rate) {
-            (Ok(1), Ok(n)) => Ok(Sample::Rate(n)),
-            (Ok(n), _    ) => Err(format!("invalid base: {}", n)),
-            _              => Err(format!("invalid rate: {}", s)),
+            (Ok(1), Ok(n)) if n > 0 => Ok(Sample::Rate(n)),
+            (Ok(1), _    )          => Err(format!("invalid rate: {}", s)),
+            (Ok(n), _    )          => Err(format!("invalid base: {}", n)),
+            _                       => Err(format!("invalid rate: {}", s)),
         }
     }
 }
+
+// DNS responses, and the TLS and HTTP ports when inspecting payloads,
+// are captured whole up to FULL_SNAPLEN bytes so they can be parsed.
+pub const FULL_SNAPLEN: u64 = 4096;
+
+const FULL:    &str = "port 53";
+const INSPECT: &str = "port 53 or tcp port 80 or tcp port 443 or tcp port 8080 or tcp port 8443";
+
+const CHECK: u64 = 1024;
+
+const XORSHIFT_MUL: u64 = 0x2545f4914f6cdd1d;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME:  u64 = 0x00000100000001b3;
+
+const BPF_RET_K: u16 = 0x06;
+const BPF_JA:    u16 = 0x05;
+
+const MAX_SNAPLEN:          i32 = 262144;
+const PCAP_NETMASK_UNKNOWN: u32 = 0xffffffff;
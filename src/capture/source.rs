@@ -2,65 +2,99 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::thread::{self, JoinHandle};
 use anyhow::Result;
-use crossbeam_channel::Sender;
-use log::{debug, info, warn};
+use crossbeam_channel::{Sender, RecvTimeoutError, bounded};
+use log::{debug, info, log, warn, Level};
 use parking_lot::Mutex;
-use crate::link::Add;
-use crate::os::setns;
-use super::{capture, Config, Sample, Timestamp};
+use serde::{Serialize, Deserialize};
+use crate::dns::Cache;
+use crate::link::{Add, Locals, locals};
+use crate::os::{getpid, ifindex, setns};
+use super::{capture, sample, Adapt, Config, Sample, Snapshot, Stats, Timestamp};
 use super::queue::Queue;
+use super::timer::Timer;
 use super::flow::Flow;
+use super::ring::Ring;
+use super::xdp::Xdp;
 use pcap::Error::*;
 
 pub struct Sources {
     pub cfg: Arc<Config>,
     pub tx:  Sender<Vec<Flow>>,
     pub map: Arc<Mutex<HashMap<String, Source>>>,
+    pub dns: Arc<Cache>,
+    pub loc: Locals,
 }
 
 #[derive(Debug)]
 pub struct Source {
-    stop: Arc<AtomicBool>,
+    stop:  Arc<AtomicBool>,
+    index: u32,
+    peer:  Option<u32>,
+    stats: Arc<Stats>,
+}
+
+// A link being captured, peer is the ifindex of a veth's peer within
+// its network namespace.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Link {
+    pub index: u32,
+    pub name:  String,
+    pub peer:  Option<u32>,
 }
 
 impl Sources {
-    pub fn new(cfg: Config, tx: Sender<Vec<Flow>>) -> Self {
+    pub fn new(cfg: Config, dns: Arc<Cache>, loc: Locals, tx: Sender<Vec<Flow>>) -> Self {
         let map = Mutex::new(HashMap::new());
         Self {
             cfg: Arc::new(cfg),
             tx:  tx,
             map: Arc::new(map),
+            dns: dns,
+            loc: loc,
         }
     }
 
-    pub fn add(&mut self, Add { name, dev, mac, netns }: Add) -> Result<()> {
+    // Start capturing a link, returning whether it was started.
+    pub fn add(&mut self, Add { name, index, peer, dev, mac, netns }: Add) -> Result<bool> {
         let name = match &netns {
             Some(_) => format!("{}-{}", name, dev),
             None    => name,
         };
 
         if !self.check(&name) {
-            return Ok(());
+            return Ok(false);
         }
 
-        let interval = time::Duration::from_std(self.cfg.interval)?;
-        let sample   = match self.cfg.sample {
-            Sample::Rate(n) => n,
-            Sample::None    => 1,
-        };
+        let cfg      = Arc::new(self.cfg.profile(&name));
+        let interval = time::Duration::from_std(cfg.interval)?;
+        let active   = time::Duration::from_std(cfg.timeouts.active)?;
+        let idle     = time::Duration::from_std(cfg.timeouts.idle)?;
+        let sample   = cfg.sample.rate();
 
         let sender = self.tx.clone();
-        let queue  = Queue::new(mac, sample, sender, interval);
         let stop   = Arc::new(AtomicBool::new(false));
 
-        let source = Source { stop: stop.clone() };
-        let cfg    = self.cfg.clone();
+        let mut queue = Queue::new(mac, sample, sender, interval);
+        queue.set_index(index);
+        queue.set_hashed(matches!(cfg.sample, Sample::Hash(_)));
+        queue.set_biflow(cfg.biflow);
+        queue.set_parse(cfg.inspect);
+        queue.set_timeouts(active, idle);
+        queue.set_dns(self.dns.clone());
+        queue.set_locals(self.loc.clone());
+
+        let source = Source {
+            stop:  stop.clone(),
+            index: index,
+            peer:  peer,
+            stats: queue.stats(),
+        };
         let map    = self.map.clone();
         self.map.lock().insert(name.clone(), source);
 
-        let mut task = Task::new(cfg, queue, stop);
+        let mut task = Task::new(cfg, queue, stop.clone(), interval);
 
         thread::spawn(move || {
             info!("starting {} capture", name);
@@ -68,15 +102,49 @@ impl Sources {
                 Ok(()) => debug!("capture {} finished", name),
                 Err(e) => warn!("capture {} stopped: {:?}", name, e),
             };
-            map.lock().remove(&name);
+
+            // The link may have been deleted and added again meanwhile.
+            let mut map = map.lock();
+            if map.get(&name).map_or(false, |s| Arc::ptr_eq(&s.stop, &stop)) {
+                map.remove(&name);
+            }
         });
 
-        Ok(())
+        Ok(true)
     }
 
-    pub fn del(&mut self, link: String) {
-        if let Some(s) = self.map.lock().get(&link) {
-            s.stop.store(true, Ordering::Release);
+    // Every link being captured.
+    pub fn links(&self) -> Vec<Link> {
+        self.map.lock().iter().map(|(name, source)| {
+            Link {
+                index: source.index,
+                name:  name.clone(),
+                peer:  source.peer,
+            }
+        }).collect()
+    }
+
+    // Capture counters of every link being captured.
+    pub fn stats(&self) -> Vec<(String, Snapshot)> {
+        self.map.lock().iter().map(|(name, source)| {
+            (name.clone(), source.stats.snapshot())
+        }).collect()
+    }
+
+    pub fn report(&self) {
+        for (name, snap) in self.stats() {
+            report(Level::Info, &name, &snap, snap.lost());
+        }
+    }
+
+    // Stop capturing a link, returning whether it was captured.
+    pub fn del(&mut self, link: String) -> bool {
+        match self.map.lock().remove(&link) {
+            Some(s) => {
+                s.stop.store(true, Ordering::Release);
+                true
+            },
+            None    => false,
         }
     }
 
@@ -104,28 +172,225 @@ struct Task {
     cfg:   Arc<Config>,
     queue: Queue,
     stop:  Arc<AtomicBool>,
+    adapt: Option<Adapt>,
+    stats: Arc<Stats>,
+    timer: Timer,
+    last:  Snapshot,
 }
 
 impl Task {
-    fn new(cfg: Arc<Config>,queue: Queue, stop: Arc<AtomicBool>) -> Self {
-        Self { cfg, queue, stop }
+    fn new(cfg: Arc<Config>, queue: Queue, stop: Arc<AtomicBool>, interval: time::Duration) -> Self {
+        let adapt = adapt(&cfg, 1);
+        let stats = queue.stats();
+        let timer = Timer::new(interval);
+        let last  = Snapshot::default();
+        Self { cfg, queue, stop, adapt, stats, timer, last }
     }
 
     fn poll(&mut self, name: &str, dev: String, netns: Option<File>) -> Result<()> {
         if let Some(ns) = netns {
             setns(&ns)?;
+            self.queue.set_locals(locals(self.stop.clone())?);
         }
 
-        let mut cap = capture(name, &dev, &self.cfg)?;
+        let result = match name {
+            _ if self.cfg.xdp.is_match(name)    => self.xdp(name, &dev),
+            _ if self.cfg.packet.is_match(name) => self.packet(name, &dev),
+            _                                   => self.pcap(name, &dev),
+        };
+
+        // Export whatever is still queued when capture stops, and stop
+        // the address watcher even when capture failed on its own.
+        self.queue.flush();
+        self.stop.store(true, Ordering::Release);
+
+        result
+    }
+
+    fn pcap(&mut self, name: &str, dev: &str) -> Result<()> {
+        let (mut cap, link, whole) = capture(name, dev, &self.cfg)?;
+        self.queue.set_datalink(link);
+        self.queue.set_whole(whole);
 
         while !self.stop.load(Ordering::Acquire) && !self.queue.done() {
-            match cap.next() {
-                Ok(packet)          => self.queue.record(packet)?,
-                Err(TimeoutExpired) => self.queue.export(Timestamp::now()),
+            let rate = match cap.next() {
+                Ok(packet)          => {
+                    self.queue.record(packet)?;
+                    self.adapt.as_mut().and_then(Adapt::record)
+                },
+                Err(TimeoutExpired) => {
+                    self.queue.export(Timestamp::now());
+                    self.adapt.as_mut().and_then(Adapt::update)
+                },
                 Err(NoMorePackets)  => break,
                 Err(e)              => return Err(e.into()),
+            };
+
+            if let Some(rate) = rate {
+                sample(&cap, rate, &self.cfg)?;
+                self.queue.set_sample(rate);
+                info!("sampling {} at 1:{}", name, rate);
+            }
+
+            if self.timer.ready(Timestamp::now()) {
+                match cap.stats() {
+                    Ok(stat) => self.stats.kernel(stat.dropped as u64, stat.if_dropped as u64),
+                    Err(e)   => warn!("capture {} stats failed: {}", name, e),
+                }
+                self.report(name);
+            }
+        }
+        Ok(())
+    }
+
+    fn xdp(&mut self, name: &str, dev: &str) -> Result<()> {
+        let mut xdp = Xdp::open(name, dev, &self.cfg)?;
+        let timeout = self.cfg.interval.as_millis() as i32;
+
+        while !self.stop.load(Ordering::Acquire) && !self.queue.done() {
+            let queue = &mut self.queue;
+            if !xdp.poll(timeout, |packet| queue.record(packet))? {
+                self.queue.export(Timestamp::now());
+            }
+
+            if self.timer.ready(Timestamp::now()) {
+                match xdp.drops() {
+                    Ok(drops) => self.stats.kernel(drops, 0),
+                    Err(e)    => warn!("capture {} stats failed: {}", name, e),
+                }
+                self.report(name);
             }
         }
         Ok(())
     }
+
+    fn packet(&mut self, name: &str, dev: &str) -> Result<()> {
+        let workers = self.cfg.fanout.max(1);
+        let group   = (getpid() ^ ifindex(dev)?) as u16;
+        let timeout = self.cfg.interval.as_millis() as i32;
+
+        let (tx, rx) = bounded(workers * 4);
+
+        let mut threads = Vec::<JoinHandle<()>>::with_capacity(workers);
+
+        for n in 0..workers {
+            let ring = match Ring::open(dev, group, workers, &self.cfg) {
+                Ok(ring) => ring,
+                Err(e)   => {
+                    self.stop.store(true, Ordering::Release);
+                    for thread in threads {
+                        let _ = thread.join();
+                    }
+                    return Err(e);
+                }
+            };
+
+            self.queue.set_datalink(ring.datalink());
+
+            let queue = self.queue.fork(tx.clone());
+            let stop  = self.stop.clone();
+            let adapt = adapt(&self.cfg, workers);
+            let timer = self.timer.clone();
+            let name  = format!("{}-{}", name, n);
+
+            threads.push(thread::spawn(move || {
+                match worker(&name, ring, queue, adapt, timer, stop, timeout) {
+                    Ok(()) => debug!("capture {} finished", name),
+                    Err(e) => warn!("capture {} stopped: {:?}", name, e),
+                }
+            }));
+        }
+
+        drop(tx);
+
+        info!("capturing {} via AF_PACKET with {} workers", name, workers);
+
+        while !self.stop.load(Ordering::Acquire) && !self.queue.done() {
+            match rx.recv_timeout(self.cfg.interval) {
+                Ok(flows)                          => self.queue.merge(flows),
+                Err(RecvTimeoutError::Timeout)      => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.queue.export(Timestamp::now());
+
+            if self.timer.ready(Timestamp::now()) {
+                self.report(name);
+            }
+        }
+
+        self.stop.store(true, Ordering::Release);
+
+        for thread in threads {
+            let _ = thread.join();
+        }
+
+        while let Ok(flows) = rx.try_recv() {
+            self.queue.merge(flows);
+        }
+
+        Ok(())
+    }
+
+    // Log counters once per interval, only visible at debug unless
+    // packets or flows were lost since the previous report.
+    fn report(&mut self, name: &str) {
+        let snap = self.stats.snapshot();
+        let lost = snap.lost().saturating_sub(self.last.lost());
+        report(Level::Debug, name, &snap, lost);
+        self.last = snap;
+    }
+}
+
+// Format a link's counters, warning instead when packets or flows were
+// lost.
+fn report(level: Level, name: &str, snap: &Snapshot, lost: u64) {
+    match lost {
+        0 => log!(level, "capture {} stats: {:?}", name, snap),
+        n => warn!("capture {} lost {} packets or flows: {:?}", name, n, snap),
+    }
+}
+
+fn worker(name: &str, mut ring: Ring, mut queue: Queue, adapt: Option<Adapt>, timer: Timer, stop: Arc<AtomicBool>, timeout: i32) -> Result<()> {
+    let result = run(name, &mut ring, &mut queue, adapt, timer, &stop, timeout);
+    queue.flush();
+    result
+}
+
+fn run(name: &str, ring: &mut Ring, queue: &mut Queue, mut adapt: Option<Adapt>, mut timer: Timer, stop: &AtomicBool, timeout: i32) -> Result<()> {
+    let stats = queue.stats();
+    while !stop.load(Ordering::Acquire) && !queue.done() {
+        let mut rate = None;
+
+        let ready = ring.poll(timeout, |packet| {
+            rate = adapt.as_mut().and_then(Adapt::record).or(rate);
+            queue.record(packet)
+        })?;
+
+        if !ready {
+            queue.export(Timestamp::now());
+            rate = adapt.as_mut().and_then(Adapt::update);
+        }
+
+        if timer.ready(Timestamp::now()) {
+            match ring.drops() {
+                Ok(drops) => stats.dropped(drops),
+                Err(e)    => warn!("capture {} stats failed: {}", name, e),
+            }
+        }
+
+        if let Some(rate) = rate {
+            ring.sample(rate)?;
+            queue.set_sample(rate);
+            info!("sampling {} at 1:{}", name, rate);
+        }
+    }
+    Ok(())
+}
+
+// Split the packet budget evenly across workers sharing a link.
+fn adapt(cfg: &Config, workers: usize) -> Option<Adapt> {
+    match cfg.sample {
+        Sample::Adaptive(budget) => Some(Adapt::new(budget / workers as u32, cfg.interval)),
+        _                        => None,
+    }
 }
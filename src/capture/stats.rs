@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    packets:     AtomicU64,
+    decoded:     AtomicU64,
+    undecodable: AtomicU64,
+    dropped:     AtomicU64,
+    if_dropped:  AtomicU64,
+    channel:     AtomicU64,
+    flows:       AtomicU64,
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Eq, PartialEq)]
+pub struct Snapshot {
+    pub packets:     u64,
+    pub decoded:     u64,
+    pub undecodable: u64,
+    pub dropped:     u64,
+    pub if_dropped:  u64,
+    pub channel:     u64,
+    pub flows:       u64,
+}
+
+impl Stats {
+    pub fn packet(&self, decoded: bool) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        match decoded {
+            true  => self.decoded.fetch_add(1, Ordering::Relaxed),
+            false => self.undecodable.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    // libpcap reports running totals since the capture was opened.
+    pub fn kernel(&self, dropped: u64, if_dropped: u64) {
+        self.dropped.store(dropped, Ordering::Relaxed);
+        self.if_dropped.store(if_dropped, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, n: u64) {
+        self.dropped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn flows(&self, n: u64) {
+        self.flows.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn channel(&self, n: u64) {
+        self.channel.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            packets:     self.packets.load(Ordering::Relaxed),
+            decoded:     self.decoded.load(Ordering::Relaxed),
+            undecodable: self.undecodable.load(Ordering::Relaxed),
+            dropped:     self.dropped.load(Ordering::Relaxed),
+            if_dropped:  self.if_dropped.load(Ordering::Relaxed),
+            channel:     self.channel.load(Ordering::Relaxed),
+            flows:       self.flows.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Snapshot {
+    // Packets dropped before decoding plus flows dropped after.
+    pub fn lost(&self) -> u64 {
+        self.dropped + self.if_dropped + self.channel
+    }
+}
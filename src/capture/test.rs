@@ -1,12 +1,1054 @@
+use std::{env, fs, process};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Result;
-use pcap::Capture;
-use crate::capture::decode;
+use crossbeam_channel::{Receiver, bounded};
+use libc::timeval;
+use pcap::{Capture, Linktype, Packet, PacketHeader};
+use pnet::util::MacAddr;
+use regex::Regex;
+use crate::capture::{decode, select, Addr, App, Config, Datalink, Direction, Flow, Key, Parse, Protocol, Random, Sample, Snapshot, Stats, Timeouts};
+use crate::collect::{Meta, Record};
+use crate::dns::{self, Cache, Data};
+use crate::link::Locals;
+use crate::packet::{self, Encap, Http};
+use super::app::{classify, port};
+use super::flow::{Biflow, Sizes, ACK, FIN, SYN};
+use crate::capture::queue::Queue;
+use crate::replay;
 
 #[test]
 fn decap() -> Result<()> {
     let mut cap = Capture::from_file("pcaps/encap.pcap")?;
     let pkt  = cap.next()?;
-    let flow = decode(None, pkt);
+    let flow = decode(Datalink::Ethernet, None, pkt, |_| Parse::Inspect);
     assert!(flow.is_some());
     Ok(())
 }
+
+#[test]
+fn replay() -> Result<()> {
+    let path = env::temp_dir().join(format!("kappa-replay-{}.pcap", process::id()));
+    let pkts = [
+        ether(IPV4, &ipv4(TCP, A, B, &tcp(50000, 80, b"GET"))),
+        ether(IPV4, &ipv4(UDP, A, B, &udp(1000, 2000, b"data"))),
+        ether(IPV4, &ipv4(TCP, A, B, &tcp(50000, 80, b"HEAD"))),
+    ];
+
+    let mut file = Capture::dead(Linktype(1))?.savefile(&path)?;
+    for (n, pkt) in pkts.iter().enumerate() {
+        file.write(&Packet::new(&header(pkt, n as i64 * 1000), pkt));
+    }
+    drop(file);
+
+    let (queue, rx) = queue(Datalink::Ethernet);
+    let random      = Random::new(1);
+    let mut flows = Vec::new();
+
+    let cap   = Capture::from_file(&path)?;
+    let count = replay::run(cap, queue, random, rx, |batch| {
+        flows.extend(batch);
+        Ok(())
+    });
+    fs::remove_file(&path)?;
+
+    assert_eq!(count?, 2);
+    flows.sort_by_key(|flow| flow.packets);
+
+    assert_eq!(flows[0].protocol, Protocol::UDP);
+    assert_eq!(flows[1].protocol, Protocol::TCP);
+    assert_eq!(flows[1].src, addr(A, 50000));
+    assert_eq!(flows[1].packets, 2);
+    assert_eq!(flows[1].bytes, pkts[0].len() + pkts[2].len() - 28);
+    assert_eq!((flows[1].start.sec, flows[1].end.sec), (0, 2));
+
+    Ok(())
+}
+
+#[test]
+fn ethernet() {
+    let pkt  = ether(IPV4, &ipv4(UDP, A, B, &udp(1000, 2000, b"data")));
+    let flow = frame(Datalink::Ethernet, &pkt).unwrap();
+
+    assert_eq!(flow.protocol, Protocol::UDP);
+    assert_eq!(flow.src, addr(A, 1000));
+    assert_eq!(flow.dst, addr(B, 2000));
+    assert_eq!(flow.packets, 1);
+    assert_eq!(flow.bytes, pkt.len() - 14);
+    assert_eq!(flow.ethernet.src, MacAddr::new(2, 0, 0, 0, 0, 2));
+}
+
+#[test]
+fn sll() {
+    let mut pkt = vec![0, 4, 0, 1, 0, 6, 2, 0, 0, 0, 0, 9, 0, 0, 0x08, 0x00];
+    pkt.extend(ipv4(TCP, A, B, &tcp(1000, 22, b"")));
+
+    let flow = frame(Datalink::SLL, &pkt).unwrap();
+    assert_eq!(flow.protocol, Protocol::TCP);
+    assert_eq!(flow.direction, Direction::Out);
+    assert_eq!(flow.ethernet.src, MacAddr::new(2, 0, 0, 0, 0, 9));
+    assert_eq!(flow.bytes, pkt.len() - 16);
+}
+
+#[test]
+fn sll2() {
+    let mut pkt = vec![0x86, 0xdd, 0, 0, 0, 0, 0, 2, 0, 1, 0, 6, 2, 0, 0, 0, 0, 9, 0, 0];
+    pkt.extend(ipv6(UDP, v6(1), v6(2), &udp(1000, 2000, b"data")));
+
+    let flow = frame(Datalink::SLL2, &pkt).unwrap();
+    assert_eq!(flow.protocol, Protocol::UDP);
+    assert_eq!(flow.direction, Direction::In);
+    assert_eq!(flow.src, Addr { addr: IpAddr::V6(v6(1)), port: 1000 });
+    assert_eq!(flow.bytes, pkt.len() - 20);
+}
+
+#[test]
+fn direction() {
+    let (mut queue, rx) = queue(Datalink::SLL);
+    let locals = Locals::default();
+    locals.insert(IpAddr::from(A));
+    queue.set_locals(locals);
+
+    let sll = |kind: u8, data: Vec<u8>| {
+        let mut pkt = vec![0, kind, 0, 1, 0, 6, 2, 0, 0, 0, 0, 9, 0, 0, 0x08, 0x00];
+        pkt.extend(data);
+        pkt
+    };
+
+    // Unicast to a local address is inbound whatever the link layer says,
+    // multicast, broadcast and link-local destinations keep it.
+    record(&mut queue, &sll(4, ipv4(UDP, B, A, &udp(1000, 2000, b"data"))), 0);
+    record(&mut queue, &sll(4, ipv4(UDP, B, [224, 0, 0, 251], &udp(5353, 5353, b"data"))), 0);
+    record(&mut queue, &sll(1, ipv4(UDP, B, [255, 255, 255, 255], &udp(68, 67, b"data"))), 0);
+    record(&mut queue, &sll(2, ipv4(UDP, B, [169, 254, 1, 1], &udp(1000, 2000, b"data"))), 0);
+    queue.flush();
+
+    let flows = drain(&rx);
+    let dir   = |dst: [u8; 4]| flows.iter().find(|f| f.dst.addr == IpAddr::from(dst)).map(|f| f.direction);
+
+    assert_eq!(flows.len(), 4);
+    assert_eq!(dir(A), Some(Direction::In));
+    assert_eq!(dir([224, 0, 0, 251]), Some(Direction::Out));
+    assert_eq!(dir([255, 255, 255, 255]), Some(Direction::In));
+    assert_eq!(dir([169, 254, 1, 1]), Some(Direction::In));
+}
+
+#[test]
+fn raw() {
+    let pkt  = ipv4(TCP, A, B, &tcp(1000, 22, b""));
+    let flow = frame(Datalink::Raw, &pkt).unwrap();
+    assert_eq!(flow.dst, addr(B, 22));
+    assert_eq!(flow.direction, Direction::Unknown);
+    assert_eq!(flow.bytes, pkt.len());
+
+    assert!(frame(Datalink::Raw, &[0x10, 0, 0, 0]).is_none());
+}
+
+#[test]
+fn ipv6_extensions() {
+    let mut ext = vec![DSTOPTS, 0, 1, 4, 0, 0, 0, 0];
+    ext.extend(&[TCP, 0, 1, 4, 0, 0, 0, 0]);
+    ext.extend(tcp(1000, 443, b"x"));
+
+    let flow = frame(Datalink::Ethernet, &ether(IPV6, &ipv6(HOPOPT, v6(1), v6(2), &ext))).unwrap();
+    assert_eq!(flow.protocol, Protocol::TCP);
+    assert_eq!(flow.dst.port, 443);
+    assert!(matches!(flow.transport, super::flow::Transport::TCP { len: 1, .. }));
+}
+
+#[test]
+fn ipv6_fragment_data() {
+    let mut rest = vec![DSTOPTS, 0, 0x00, 0x08, 0, 0, 0, 7];
+    rest.extend(&[UDP, 0, 0, 0, 0, 0, 0, 0]);
+    rest.extend(b"trailing");
+
+    let flow = frame(Datalink::Raw, &ipv6(FRAGMENT, v6(1), v6(2), &rest)).unwrap();
+    assert_eq!(flow.protocol, Protocol::Other(DSTOPTS as u16));
+    assert_eq!(flow.bytes, rest.len() + 40);
+    assert!(matches!(flow.transport, super::flow::Transport::Other));
+}
+
+#[test]
+fn ipv6_fragments() {
+    let mut first = vec![UDP, 0, 0x00, 0x01, 0, 0, 0, 7];
+    first.extend(udp(1000, 2000, b"data"));
+
+    let flow = frame(Datalink::Raw, &ipv6(FRAGMENT, v6(1), v6(2), &first)).unwrap();
+    assert_eq!(flow.protocol, Protocol::UDP);
+    assert_eq!(flow.dst.port, 2000);
+    assert_eq!(flow.fragments, 1);
+    assert_eq!((flow.fragment.id, flow.fragment.offset, flow.fragment.more), (7, 0, true));
+
+    let mut rest = vec![UDP, 0, 0x00, 0x08, 0, 0, 0, 7];
+    rest.extend(b"trailing");
+
+    let flow = frame(Datalink::Raw, &ipv6(FRAGMENT, v6(1), v6(2), &rest)).unwrap();
+    assert_eq!(flow.protocol, Protocol::Other(UDP as u16));
+    assert_eq!(flow.dst.port, 0);
+    assert_eq!((flow.fragment.id, flow.fragment.offset, flow.fragment.more), (7, 8, false));
+}
+
+#[test]
+fn ipv4_fragments() {
+    let mut pkt = ipv4(UDP, A, B, b"trailing");
+    pkt[6..8].copy_from_slice(&[0x00, 0xb9]);
+
+    let flow = frame(Datalink::Raw, &pkt).unwrap();
+    assert_eq!(flow.protocol, Protocol::Other(UDP as u16));
+    assert_eq!(flow.fragments, 1);
+    assert_eq!(flow.fragment.offset, 1480);
+}
+
+#[test]
+fn fragment_flows() {
+    let mut first = ipv4(UDP, A, B, &udp(1000, 2000, b"data"));
+    first[6] = 0x20;
+
+    let mut rest = ipv4(UDP, A, B, b"trailing");
+    rest[6..8].copy_from_slice(&[0x00, 0xb9]);
+
+    let mut other = ipv4(UDP, A, B, b"trailing");
+    other[4..8].copy_from_slice(&[0x43, 0x21, 0x00, 0xb9]);
+
+    let (mut queue, rx) = queue(Datalink::Raw);
+    record(&mut queue, &first, 0);
+    record(&mut queue, &rest,  1);
+    record(&mut queue, &other, 2);
+    queue.flush();
+
+    let mut flows = drain(&rx);
+    flows.sort_by_key(|flow| flow.dst.port);
+
+    assert_eq!(flows.len(), 2);
+    assert_eq!(flows[0].protocol, Protocol::Other(UDP as u16));
+    assert_eq!(flows[1].dst, addr(B, 2000));
+    assert_eq!((flows[1].packets, flows[1].fragments), (2, 2));
+}
+
+#[test]
+fn vxlan() {
+    let inner = ether(IPV4, &ipv4(TCP, C, D, &tcp(1000, 80, b"")));
+
+    let mut vx = vec![0x08, 0, 0, 0, 0, 0, 42, 0];
+    vx.extend(&inner);
+
+    let pkt  = ether(IPV4, &ipv4(UDP, A, B, &udp(50000, 4789, &vx)));
+    let flow = frame(Datalink::Ethernet, &pkt).unwrap();
+
+    assert_eq!(flow.src, addr(C, 1000));
+    assert_eq!(flow.dst, addr(D, 80));
+    assert_eq!(flow.bytes, inner.len() - 14);
+
+    let tunnel = flow.tunnel.unwrap();
+    assert_eq!(tunnel.encap, Encap::VXLAN);
+    assert_eq!(tunnel.id, 42);
+    assert_eq!((tunnel.src, tunnel.dst), (IpAddr::from(A), IpAddr::from(B)));
+}
+
+#[test]
+fn geneve() {
+    let mut gnv = vec![0, 0, 0x65, 0x58, 0, 0, 9, 0];
+    gnv.extend(ether(IPV4, &ipv4(UDP, C, D, &udp(1000, 2000, b"data"))));
+
+    let pkt  = ether(IPV4, &ipv4(UDP, A, B, &udp(50000, 6081, &gnv)));
+    let flow = frame(Datalink::Ethernet, &pkt).unwrap();
+
+    assert_eq!(flow.dst, addr(D, 2000));
+
+    let tunnel = flow.tunnel.unwrap();
+    assert_eq!(tunnel.encap, Encap::Geneve);
+    assert_eq!(tunnel.id, 9);
+}
+
+#[test]
+fn gre() {
+    let mut gre = vec![0x20, 0x00, 0x08, 0x00, 0, 0, 0, 7];
+    gre.extend(ipv4(UDP, C, D, &udp(1000, 2000, b"data")));
+
+    let pkt  = ether(IPV4, &ipv4(GRE, A, B, &gre));
+    let flow = frame(Datalink::Ethernet, &pkt).unwrap();
+
+    assert_eq!(flow.dst, addr(D, 2000));
+    assert_eq!(flow.bytes, pkt.len() - 14 - 20 - 8);
+
+    let tunnel = flow.tunnel.unwrap();
+    assert_eq!(tunnel.encap, Encap::GRE);
+    assert_eq!(tunnel.id, 7);
+}
+
+#[test]
+fn ipip() {
+    let pkt  = ipv4(IPIP, A, B, &ipv4(UDP, C, D, &udp(1000, 2000, b"data")));
+    let flow = frame(Datalink::Raw, &pkt).unwrap();
+
+    assert_eq!(flow.src, addr(C, 1000));
+    assert_eq!(flow.tunnel.unwrap().encap, Encap::IPIP);
+}
+
+#[test]
+fn decap_fallback() {
+    let mut vx = vec![0x08, 0, 0, 0, 0, 0, 42, 0];
+    vx.extend(ether(0x88cc, &[0; 32]));
+
+    let pkt  = ether(IPV4, &ipv4(UDP, A, B, &udp(50000, 4789, &vx)));
+    let flow = frame(Datalink::Ethernet, &pkt).unwrap();
+
+    assert_eq!(flow.dst, addr(B, 4789));
+    assert_eq!(flow.bytes, pkt.len() - 14);
+    assert!(flow.tunnel.is_none());
+}
+
+#[test]
+fn vlan() {
+    let mut tag = vec![0x00, 0x64, 0x08, 0x00];
+    tag.extend(ipv4(UDP, A, B, &udp(1000, 2000, b"data")));
+
+    let flow = frame(Datalink::Ethernet, &ether(0x8100, &tag)).unwrap();
+    assert_eq!(flow.ethernet.vlans.as_slice(), &[100]);
+    assert_eq!(flow.dst, addr(B, 2000));
+
+    let mut qinq = vec![0x00, 0x0a, 0x81, 0x00];
+    qinq.extend(&tag);
+
+    let flow = frame(Datalink::Ethernet, &ether(0x88a8, &qinq)).unwrap();
+    assert_eq!(flow.ethernet.vlans.as_slice(), &[10, 100]);
+    assert_eq!(flow.dst, addr(B, 2000));
+}
+
+#[test]
+fn mpls() {
+    let mut stack = vec![0x00, 0x06, 0x40, 0x40, 0x00, 0x0c, 0x81, 0x40];
+    stack.extend(ipv4(UDP, A, B, &udp(1000, 2000, b"data")));
+
+    let flow = frame(Datalink::Ethernet, &ether(0x8847, &stack)).unwrap();
+    assert_eq!(flow.mpls.labels.as_slice(), &[100, 200]);
+    assert_eq!(flow.mpls.ethertype, 0x8847);
+    assert_eq!(flow.dst, addr(B, 2000));
+}
+
+#[test]
+fn retransmits() {
+    let segments: &[(u32, &[u8])] = &[
+        (1, b"aaaa"),
+        (5, b"bbbb"),
+        (5, b"bbbb"),
+        (9, b""),
+        (1, b"aaaa"),
+        (9, b"cccc"),
+    ];
+
+    let (mut queue, rx) = queue(Datalink::Raw);
+    for (n, (seq, payload)) in segments.iter().enumerate() {
+        record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, *seq, ACK, payload)), n as i64);
+    }
+    record(&mut queue, &ipv4(TCP, B, A, &segment(80, 50000, 1, ACK, b"aaaa")), 6);
+    queue.flush();
+
+    let mut flows = drain(&rx);
+    flows.sort_by_key(|flow| flow.src.port);
+
+    assert_eq!(flows[0].retransmits, 0);
+    assert_eq!(flows[1].retransmits, 2);
+    assert_eq!(flows[1].packets, 6);
+}
+
+#[test]
+fn handshake() {
+    let (mut queue, rx) = queue(Datalink::Raw);
+    record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, 1, SYN, b"")), 0);
+    record(&mut queue, &ipv4(TCP, B, A, &segment(80, 50000, 9, SYN | ACK, b"")), 10);
+    record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, 2, ACK, b"")), 30);
+    queue.flush();
+
+    let flows = drain(&rx);
+    assert_eq!(flows.len(), 2);
+
+    for flow in flows {
+        assert_eq!(flow.rtt.server, Duration::from_millis(10));
+        assert_eq!(flow.rtt.client, Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn biflow() {
+    let (mut queue, rx) = queue(Datalink::Raw);
+    queue.set_biflow(true);
+
+    record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, 1, SYN, b"")), 0);
+    record(&mut queue, &ipv4(TCP, B, A, &segment(80, 50000, 9, SYN | ACK, b"")), 1);
+    record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, 2, ACK, b"")), 2);
+    record(&mut queue, &ipv4(TCP, B, A, &segment(80, 50000, 10, ACK, b"data")), 3);
+
+    record(&mut queue, &ipv4(UDP, D, C, &udp(53, 40000, b"data")), 4);
+    record(&mut queue, &ipv4(UDP, C, D, &udp(40000, 53, b"data")), 5);
+    record(&mut queue, &ipv4(UDP, C, D, &udp(40000, 53, b"data")), 6);
+    queue.flush();
+
+    let mut flows = drain(&rx);
+    flows.sort_by_key(|flow| flow.src.port);
+    assert_eq!(flows.len(), 2);
+
+    let (udp, tcp) = (&flows[0], &flows[1]);
+
+    assert_eq!(tcp.biflow, Biflow::Initiator);
+    assert_eq!((tcp.src, tcp.dst), (addr(A, 50000), addr(B, 80)));
+    assert_eq!((tcp.packets, tcp.reverse.packets), (2, 2));
+    assert_eq!(tcp.reverse.bytes, 84);
+
+    assert_eq!(udp.biflow, Biflow::Canonical);
+    assert_eq!((udp.src, udp.dst), (addr(C, 40000), addr(D, 53)));
+    assert_eq!((udp.packets, udp.reverse.packets), (2, 1));
+}
+
+#[test]
+fn expire() {
+    let (mut queue, rx) = queue(Datalink::Raw);
+    queue.set_timeouts(time::Duration::seconds(10), time::Duration::seconds(3));
+
+    let active = ipv4(UDP, A, B, &udp(1000, 2000, b"data"));
+    let idle   = ipv4(UDP, C, D, &udp(1000, 2000, b"data"));
+
+    record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, 1, FIN | ACK, b"")), 0);
+    record(&mut queue, &idle, 0);
+    record(&mut queue, &active, 0);
+
+    let flows = drain(&rx);
+    assert_eq!(flows.len(), 1);
+    assert!(flows[0].closed);
+
+    record(&mut queue, &active, 2000);
+    record(&mut queue, &active, 4000);
+
+    let flows = drain(&rx);
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].src, addr(C, 1000));
+
+    for sec in 3..6 {
+        record(&mut queue, &active, sec * 2000);
+    }
+
+    let flows = drain(&rx);
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].src, addr(A, 1000));
+    assert_eq!(flows[0].packets, 6);
+    assert_eq!((flows[0].start.sec, flows[0].end.sec), (0, 10));
+
+    record(&mut queue, &active, 12000);
+    queue.flush();
+
+    let flows = drain(&rx);
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].packets, 1);
+}
+
+#[test]
+fn dns_response() {
+    let pkt  = ipv4(UDP, B, A, &udp(53, 40000, &response(0x8180)));
+    let flow = frame(Datalink::Raw, &pkt).unwrap();
+    let dns  = flow.dns.unwrap();
+
+    assert_eq!(dns.question, "example.com");
+    assert_eq!(dns.host, IpAddr::from(A));
+    assert_eq!(dns.answers.len(), 2);
+
+    assert_eq!(dns.answers[0].name, "example.com");
+    assert!(matches!(&dns.answers[0].data, Data::CNAME(name) if name == "www.example.com"));
+
+    assert_eq!(dns.answers[1].ttl, 300);
+    assert!(matches!(dns.answers[1].data, Data::Addr(ip) if ip == IpAddr::from([1, 2, 3, 4])));
+}
+
+#[test]
+fn dns_invalid() {
+    let host = IpAddr::from(A);
+    assert!(dns::parse(host, &response(0x0100)).is_none());
+    assert!(dns::parse(host, &response(0x8183)).is_none());
+
+    let msg = response(0x8180);
+    assert!(dns::parse(host, &msg[..msg.len() - 2]).map_or(false, |r| r.answers.len() == 1));
+
+    let mut lo = msg.clone();
+    lo[12..14].copy_from_slice(&[0xc0, 0x0c]);
+    assert!(dns::parse(host, &lo).is_none());
+}
+
+#[test]
+fn dns_cache() {
+    let cache = Arc::new(Cache::new(true));
+
+    let (mut queue, _rx) = queue(Datalink::Raw);
+    queue.set_dns(cache.clone());
+    record(&mut queue, &ipv4(UDP, B, A, &udp(53, 40000, &response(0x8180))), 0);
+
+    let mut records = vec![Record {
+        flow: Flow {
+            src: addr(A, 40000),
+            dst: addr([1, 2, 3, 4], 443),
+            ..Default::default()
+        },
+        src:  Meta::default(),
+        dst:  Meta::default(),
+        srtt: Duration::default(),
+    }];
+
+    cache.merge(&mut records);
+    assert_eq!(records[0].src.name, None);
+    assert_eq!(records[0].dst.name.as_deref().map(String::as_str), Some("example.com"));
+
+    assert_eq!(cache.drain().len(), 1);
+    assert!(cache.drain().is_empty());
+}
+
+#[test]
+fn dns_truncated() {
+    let cache = Arc::new(Cache::new(true));
+
+    let (mut queue, _rx) = queue(Datalink::Raw);
+    queue.set_dns(cache.clone());
+    queue.set_whole(false);
+    record(&mut queue, &ipv4(UDP, B, A, &udp(53, 40000, &response(0x8180))), 0);
+
+    assert!(cache.drain().is_empty());
+}
+
+#[test]
+fn tls_client_hello() {
+    let pkt  = ipv4(TCP, A, B, &tcp(50000, 443, &hello("Example.com", &["h2", "http/1.1"])));
+    let flow = frame(Datalink::Raw, &pkt).unwrap();
+    let tls  = flow.tls.unwrap();
+
+    assert_eq!(tls.sni.as_deref(), Some("example.com"));
+    assert_eq!(tls.alpn.as_deref(), Some("h2,http/1.1"));
+    assert_eq!(flow.app, App::TLS);
+
+    let msg = hello("example.com", &["h2"]);
+    assert!(packet::client_hello(&msg[..60]).is_none());
+    assert!(packet::client_hello(b"\x16\x03\x01\x00\x05\x02").is_none());
+}
+
+#[test]
+fn http() {
+    let req  = b"GET /a/b/c?x=1 HTTP/1.1\r\nHost: Example.com\r\n\r\n";
+    let flow = frame(Datalink::Raw, &ipv4(TCP, A, B, &tcp(50000, 80, req))).unwrap();
+    let http = flow.http.unwrap();
+
+    assert_eq!(http.method.as_deref(), Some("GET"));
+    assert_eq!(http.host.as_deref(), Some("example.com"));
+    assert_eq!(http.path.as_deref(), Some("/a/b"));
+    assert_eq!(flow.app, App::HTTP);
+
+    let res = packet::http(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap();
+    assert_eq!(res.status, Some(404));
+    assert_eq!(http.merge(&res), Http {
+        method: Some("GET".to_owned()),
+        host:   Some("example.com".to_owned()),
+        path:   Some("/a/b".to_owned()),
+        status: Some(404),
+    });
+
+    assert!(packet::http(b"FOO / HTTP/1.1\r\n").is_none());
+    assert!(packet::http(b"GET / SPDY/3\r\n").is_none());
+}
+
+#[test]
+fn inspect() {
+    let pkt  = ipv4(TCP, A, B, &tcp(50000, 443, &hello("example.com", &["h2"])));
+    let hdr  = header(&pkt, 0);
+
+    let flow = decode(Datalink::Raw, None, Packet::new(&hdr, &pkt), |_| Parse::Classify).unwrap();
+    assert!(flow.tls.is_none());
+    assert_eq!(flow.app, App::TLS);
+
+    let flow = decode(Datalink::Raw, None, Packet::new(&hdr, &pkt), |_| Parse::None).unwrap();
+    assert!(flow.tls.is_none());
+    assert_eq!(flow.app, App::Unknown);
+}
+
+#[test]
+fn app() {
+    let mut mysql = vec![10, 0, 0, 0, 10];
+    mysql.extend(b"8.0.0\0");
+    mysql.extend(&[1, 2, 3]);
+
+    assert_eq!(classify(Protocol::TCP, b"SSH-2.0-OpenSSH_8.9\r\n"), App::SSH);
+    assert_eq!(classify(Protocol::TCP, b"*1\r\n$4\r\nPING\r\n"), App::Redis);
+    assert_eq!(classify(Protocol::TCP, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), App::HTTP2);
+    assert_eq!(classify(Protocol::TCP, &[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]), App::Postgres);
+    assert_eq!(classify(Protocol::TCP, &mysql), App::MySQL);
+    assert_eq!(classify(Protocol::TCP, b"hello world"), App::Unknown);
+    assert_eq!(classify(Protocol::UDP, &response(0x0100)), App::DNS);
+    assert_eq!(classify(Protocol::ICMP, b"SSH-"), App::Unknown);
+
+    assert_eq!(port(Protocol::TCP, addr(A, 50000), addr(B, 5432)), App::Postgres);
+    assert_eq!(port(Protocol::UDP, addr(A, 53), addr(B, 50000)), App::DNS);
+    assert_eq!(port(Protocol::UDP, addr(A, 5432), addr(B, 50000)), App::Unknown);
+}
+
+#[test]
+fn classify_default() {
+    let (mut queue, rx) = queue(Datalink::Raw);
+
+    record(&mut queue, &ipv4(TCP, A, B, &tcp(50000, 7000, b"*1\r\n$4\r\nPING\r\n")), 0);
+    record(&mut queue, &ipv4(TCP, A, C, &tcp(50000, 7001, &[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])), 0);
+    record(&mut queue, &ipv4(TCP, A, D, &tcp(50000, 443, &hello("example.com", &["h2"]))), 0);
+    queue.flush();
+
+    let mut flows = drain(&rx);
+    flows.sort_by_key(|flow| flow.dst.port);
+
+    assert_eq!(flows.len(), 3);
+    assert_eq!(flows[0].app, App::TLS);
+    assert_eq!(flows[1].app, App::Redis);
+    assert_eq!(flows[2].app, App::Postgres);
+    assert!(flows[0].tls.is_none());
+}
+
+#[test]
+fn parse_biflow() {
+    let (mut queue, rx) = queue(Datalink::Raw);
+    queue.set_biflow(true);
+    queue.set_parse(true);
+
+    for n in 0..8 {
+        record(&mut queue, &ipv4(TCP, A, B, &segment(50000, 80, 1 + n, ACK, b"?")), n as i64);
+    }
+    record(&mut queue, &ipv4(TCP, B, A, &tcp(80, 50000, b"HTTP/1.1 200 OK\r\n\r\n")), 8);
+    queue.flush();
+
+    let flows = drain(&rx);
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].reverse.packets, 1);
+    assert!(flows[0].http.is_none());
+}
+
+#[test]
+fn sizes() {
+    let mut sizes = Sizes::default();
+    for &size in &[64, 128, 255, 256, 1500, 2048, 9000] {
+        sizes.record(size);
+    }
+
+    assert_eq!(sizes.hist, [1, 2, 1, 0, 1, 2]);
+    assert_eq!((sizes.min, sizes.max), (64, 9000));
+
+    let mut other = Sizes::default();
+    other.record(40);
+    sizes.merge(&other);
+
+    assert_eq!(sizes.hist, [2, 2, 1, 0, 1, 2]);
+    assert_eq!((sizes.min, sizes.max), (40, 9000));
+}
+
+#[test]
+fn resample() {
+    let mut flow = counted(1, 3, 1000);
+    flow.reverse.packets = 0;
+    flow.sizes.hist = [14, 0, 0, 0, 0, 6];
+    flow.resample(10);
+
+    assert_eq!(flow.sample, 10);
+    assert_eq!(flow.bytes, 100);
+    assert_eq!(flow.packets, 1);
+    assert_eq!(flow.reverse.packets, 0);
+    assert_eq!(flow.sizes.hist, [1, 0, 0, 0, 0, 1]);
+
+    let mut flow = counted(4, 8, 800);
+    flow.resample(2);
+    assert_eq!((flow.sample, flow.packets, flow.bytes), (4, 8, 800));
+
+    let mut flow = counted(0, 8, 800);
+    flow.resample(4);
+    assert_eq!((flow.sample, flow.packets, flow.bytes), (4, 2, 200));
+}
+
+#[test]
+fn merge() {
+    let mut a = counted(1, 10, 1000);
+    let b     = counted(4, 2, 400);
+    a.merge(&b);
+
+    assert_eq!(a.sample, 4);
+    assert_eq!(a.packets, 5);
+    assert_eq!(a.bytes, 650);
+
+    let mut c = counted(4, 2, 400);
+    c.biflow = Biflow::Canonical;
+    let mut d = counted(4, 1, 100);
+    d.biflow = Biflow::Initiator;
+    d.app    = App::DNS;
+    c.merge(&d);
+
+    assert_eq!((c.packets, c.bytes), (3, 500));
+    assert_eq!(c.biflow, Biflow::Initiator);
+    assert_eq!(c.app, App::DNS);
+
+    // An unset rate of zero merges as unsampled in either order.
+    let mut e = counted(4, 2, 400);
+    e.merge(&counted(0, 8, 800));
+    assert_eq!((e.sample, e.packets, e.bytes), (4, 4, 600));
+
+    let mut f = counted(0, 8, 800);
+    f.merge(&counted(4, 2, 400));
+    assert_eq!((f.sample, f.packets, f.bytes), (4, 4, 600));
+
+    let mut g = counted(0, 1, 100);
+    g.merge(&counted(0, 1, 100));
+    assert_eq!((g.sample, g.packets, g.bytes), (0, 2, 200));
+}
+
+#[test]
+fn select_flows() {
+    let key = Key(Protocol::TCP, addr(A, 1000), addr(B, 80));
+    assert!(select(&key, 1));
+
+    for port in 0..1000 {
+        let key = Key(Protocol::TCP, addr(A, port), addr(B, 80));
+        assert_eq!(select(&key, 4), select(&key.reverse(), 4));
+    }
+
+    let n = (0..4000).filter(|&port| {
+        select(&Key(Protocol::UDP, addr(A, port), addr(B, 53)), 4)
+    }).count();
+    assert!(n > 700 && n < 1300, "selected {} of 4000", n);
+}
+
+#[test]
+fn sample_parse() {
+    assert!(matches!("1:100".parse::<Sample>(),     Ok(Sample::Rate(100))));
+    assert!(matches!("hash:8".parse::<Sample>(),    Ok(Sample::Hash(8))));
+    assert!(matches!("auto:5000".parse::<Sample>(), Ok(Sample::Adaptive(5000))));
+    assert!("2:100".parse::<Sample>().is_err());
+    assert!("100".parse::<Sample>().is_err());
+    assert!("1:0".parse::<Sample>().is_err());
+    assert!("hash:0".parse::<Sample>().is_err());
+    assert!("auto:0".parse::<Sample>().is_err());
+}
+
+#[test]
+fn stats() {
+    let stats = Stats::default();
+    stats.packet(true);
+    stats.packet(true);
+    stats.packet(false);
+    stats.kernel(5, 1);
+    stats.dropped(2);
+    stats.flows(4);
+    stats.channel(3);
+
+    let snap = stats.snapshot();
+    assert_eq!(snap, Snapshot {
+        packets:     3,
+        decoded:     2,
+        undecodable: 1,
+        dropped:     7,
+        if_dropped:  1,
+        channel:     3,
+        flows:       4,
+    });
+    assert_eq!(snap.lost(), 11);
+
+    // Kernel counters are running totals and replace what was recorded.
+    stats.kernel(6, 1);
+    assert_eq!(stats.snapshot().dropped, 6);
+    assert_eq!(stats.snapshot().lost(), 10);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn filter_splice() -> Result<()> {
+    use super::sample::program;
+
+    let dns  = ether(IPV4, &ipv4(UDP, B, A, &udp(53, 40000, &response(0x8180))));
+    let data = ether(IPV4, &ipv4(UDP, A, B, &udp(1000, 2000, b"data")));
+    let tls  = ether(IPV4, &ipv4(TCP, A, B, &tcp(50000, 443, b"")));
+
+    // Accepted packets are truncated unless they are DNS, and everything
+    // the expression rejects is dropped.
+    let cfg = config(Some("udp"), false)?;
+    let ops = program(1, &cfg, 1)?;
+    assert_eq!(bpf(&ops, &dns,  0), 0xffffffff);
+    assert_eq!(bpf(&ops, &data, 0), 128);
+    assert_eq!(bpf(&ops, &tls,  0), 0);
+
+    // Inspection keeps TLS whole.
+    let cfg = config(None, true)?;
+    let ops = program(1, &cfg, 1)?;
+    assert_eq!(bpf(&ops, &tls,  0), 0xffffffff);
+    assert_eq!(bpf(&ops, &data, 0), 128);
+
+    // Sampling applies only to packets accepted by the expression.
+    let cfg = config(Some("udp"), false)?;
+    let ops = program(10, &cfg, 1)?;
+    assert_eq!(bpf(&ops, &data, 11), 128);
+    assert_eq!(bpf(&ops, &data, 12), 0);
+    assert_eq!(bpf(&ops, &tls,  11), 0);
+
+    Ok(())
+}
+
+fn queue(link: Datalink) -> (Queue, Receiver<Vec<Flow>>) {
+    let (tx, rx) = bounded(16);
+    let mut queue = Queue::new(None, 1, tx, time::Duration::seconds(1));
+    queue.set_datalink(link);
+    (queue, rx)
+}
+
+fn record(queue: &mut Queue, pkt: &[u8], ms: i64) {
+    queue.record(Packet::new(&header(pkt, ms), pkt)).unwrap();
+}
+
+fn drain(rx: &Receiver<Vec<Flow>>) -> Vec<Flow> {
+    rx.try_iter().flatten().collect()
+}
+
+fn frame(link: Datalink, data: &[u8]) -> Option<Flow> {
+    let hdr = header(data, 0);
+    decode(link, None, Packet::new(&hdr, data), |_| Parse::Inspect)
+}
+
+fn header(data: &[u8], ms: i64) -> PacketHeader {
+    PacketHeader {
+        ts:     timeval { tv_sec: ms / 1000, tv_usec: ms % 1000 * 1000 },
+        caplen: data.len() as u32,
+        len:    data.len() as u32,
+    }
+}
+
+fn counted(sample: u32, packets: usize, bytes: usize) -> Flow {
+    Flow {
+        sample:  sample,
+        packets: packets,
+        bytes:   bytes,
+        ..Default::default()
+    }
+}
+
+fn addr(ip: [u8; 4], port: u16) -> Addr {
+    Addr {
+        addr: IpAddr::from(ip),
+        port: port,
+    }
+}
+
+fn v6(n: u16) -> Ipv6Addr {
+    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n)
+}
+
+fn ether(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut pkt = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2];
+    pkt.extend(&ethertype.to_be_bytes());
+    pkt.extend(payload);
+    pkt
+}
+
+fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let len = (20 + payload.len()) as u16;
+    let mut pkt = vec![0x45, 0];
+    pkt.extend(&len.to_be_bytes());
+    pkt.extend(&[0x12, 0x34, 0, 0, 64, protocol, 0, 0]);
+    pkt.extend(&src);
+    pkt.extend(&dst);
+    pkt.extend(payload);
+    pkt
+}
+
+fn ipv6(next: u8, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len() as u16;
+    let mut pkt = vec![0x60, 0, 0, 0];
+    pkt.extend(&len.to_be_bytes());
+    pkt.extend(&[next, 64]);
+    pkt.extend(&src.octets());
+    pkt.extend(&dst.octets());
+    pkt.extend(payload);
+    pkt
+}
+
+fn udp(src: u16, dst: u16, payload: &[u8]) -> Vec<u8> {
+    let len = (8 + payload.len()) as u16;
+    let mut pkt = Vec::new();
+    pkt.extend(&src.to_be_bytes());
+    pkt.extend(&dst.to_be_bytes());
+    pkt.extend(&len.to_be_bytes());
+    pkt.extend(&[0, 0]);
+    pkt.extend(payload);
+    pkt
+}
+
+fn tcp(src: u16, dst: u16, payload: &[u8]) -> Vec<u8> {
+    segment(src, dst, 1, PSH | ACK, payload)
+}
+
+fn segment(src: u16, dst: u16, seq: u32, flags: u16, payload: &[u8]) -> Vec<u8> {
+    let mut pkt = Vec::new();
+    pkt.extend(&src.to_be_bytes());
+    pkt.extend(&dst.to_be_bytes());
+    pkt.extend(&seq.to_be_bytes());
+    pkt.extend(&[0, 0, 0, 0, 0x50, flags as u8, 0x04, 0x00, 0, 0, 0, 0]);
+    pkt.extend(payload);
+    pkt
+}
+
+// Response to an A query for example.com with a CNAME to www and its
+// address, names compressed against the question.
+fn response(flags: u16) -> Vec<u8> {
+    let mut msg = vec![0x12, 0x34];
+    msg.extend(&flags.to_be_bytes());
+    msg.extend(&[0, 1, 0, 2, 0, 0, 0, 0]);
+    msg.extend(b"\x07example\x03com\x00");
+    msg.extend(&[0, 1, 0, 1]);
+
+    msg.extend(&[0xc0, 0x0c, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+    msg.extend(b"\x03www\xc0\x0c");
+
+    msg.extend(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 1, 0x2c, 0, 4]);
+    msg.extend(&[1, 2, 3, 4]);
+    msg
+}
+
+fn hello(sni: &str, alpn: &[&str]) -> Vec<u8> {
+    let mut name = vec![0];
+    name.extend(&(sni.len() as u16).to_be_bytes());
+    name.extend(sni.as_bytes());
+
+    let mut protos = Vec::new();
+    for proto in alpn {
+        protos.push(proto.len() as u8);
+        protos.extend(proto.as_bytes());
+    }
+
+    let mut exts = Vec::<u8>::new();
+    for (kind, list) in &[(0u16, name), (16u16, protos)] {
+        exts.extend(&kind.to_be_bytes());
+        exts.extend(&(list.len() as u16 + 2).to_be_bytes());
+        exts.extend(&(list.len() as u16).to_be_bytes());
+        exts.extend(list);
+    }
+
+    let mut body = vec![3, 3];
+    body.extend(&[0; 32]);
+    body.extend(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend(&(exts.len() as u16).to_be_bytes());
+    body.extend(exts);
+
+    let mut msg = vec![1];
+    msg.extend(&(body.len() as u32).to_be_bytes()[1..]);
+    msg.extend(body);
+
+    let mut rec = vec![22, 3, 1];
+    rec.extend(&(msg.len() as u16).to_be_bytes());
+    rec.extend(msg);
+    rec
+}
+
+#[cfg(target_os = "linux")]
+fn config(filter: Option<&str>, inspect: bool) -> Result<Config> {
+    Ok(Config {
+        capture:     Regex::new(".*")?,
+        exclude:     Regex::new("^$")?,
+        xdp:         Regex::new("^$")?,
+        mirror:      Regex::new("^$")?,
+        packet:      Regex::new("^$")?,
+        fanout:      1,
+        interval:    Duration::from_secs(1),
+        timeouts:    Timeouts {
+            active: Duration::from_secs(60),
+            idle:   Duration::from_secs(15),
+        },
+        buffer_size: 0,
+        sample:      Sample::None,
+        filter:      filter.map(str::to_owned),
+        snaplen:     128,
+        inspect:     inspect,
+        promisc:     false,
+        biflow:      false,
+        profiles:    Vec::new(),
+    })
+}
+
+// Run a classic BPF program over a packet, loading rand for the random
+// ancillary field.
+#[cfg(target_os = "linux")]
+fn bpf(ops: &[bpf::Op], pkt: &[u8], rand: u32) -> u32 {
+    let load = |k: usize, n: usize| pkt.get(k..k+n).map(|b| {
+        b.iter().fold(0u32, |v, &b| (v << 8) | b as u32)
+    });
+
+    let size = |code: u16| match code & 0x18 {
+        0x00 => 4,
+        0x08 => 2,
+        _    => 1,
+    };
+
+    let (mut a, mut x, mut mem) = (0u32, 0u32, [0u32; 16]);
+    let mut pc = 0;
+
+    loop {
+        let op = &ops[pc];
+        let k  = op.k;
+        pc += 1;
+
+        match op.code {
+            0x20 if k == 0xfffff038 => a = rand,
+            0x20 | 0x28 | 0x30      => match load(k as usize, size(op.code)) {
+                Some(v) => a = v,
+                None    => return 0,
+            },
+            0x40 | 0x48 | 0x50      => match load((x + k) as usize, size(op.code)) {
+                Some(v) => a = v,
+                None    => return 0,
+            },
+            0xb1                    => match load(k as usize, 1) {
+                Some(v) => x = (v & 0xf) * 4,
+                None    => return 0,
+            },
+            0x00 => a = k,
+            0x01 => x = k,
+            0x02 => mem[k as usize] = a,
+            0x03 => mem[k as usize] = x,
+            0x60 => a = mem[k as usize],
+            0x61 => x = mem[k as usize],
+            0x07 => x = a,
+            0x87 => a = x,
+            0x04 => a = a.wrapping_add(k),
+            0x54 => a &= k,
+            0x64 => a <<= k,
+            0x74 => a >>= k,
+            0x94 => a %= k,
+            0x05 => pc += k as usize,
+            0x15 | 0x25 | 0x35 | 0x45 => {
+                let hit = match op.code {
+                    0x15 => a == k,
+                    0x25 => a > k,
+                    0x35 => a >= k,
+                    _    => a & k != 0,
+                };
+                pc += match hit {
+                    true  => op.jt,
+                    false => op.jf,
+                } as usize;
+            },
+            0x06 => return k,
+            0x16 => return a,
+            code => panic!("unsupported BPF op {:#x}", code),
+        }
+    }
+}
+
+const A: [u8; 4] = [10, 0, 0, 1];
+const B: [u8; 4] = [10, 0, 0, 2];
+const C: [u8; 4] = [192, 168, 0, 1];
+const D: [u8; 4] = [192, 168, 0, 2];
+
+const IPV4: u16 = 0x0800;
+const IPV6: u16 = 0x86dd;
+
+const PSH: u16 = 0b01000;
+
+const HOPOPT:   u8 = 0;
+const IPIP:     u8 = 4;
+const TCP:      u8 = 6;
+const UDP:      u8 = 17;
+const GRE:      u8 = 47;
+const FRAGMENT: u8 = 44;
+const DSTOPTS:  u8 = 60;
@@ -1,6 +1,7 @@
 use time::Duration;
 use super::Timestamp;
 
+#[derive(Clone)]
 pub struct Timer {
     delay: Duration,
     next:  Timestamp,
@@ -0,0 +1,296 @@
+use std::cmp::{max, min};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::os::raw::{c_int, c_void};
+use std::slice;
+use anyhow::{Result, anyhow};
+use ebpf::ffi::{bpf_insn, bpf_map_create_arg, bpf_prog_load_arg, xdp_desc, BPF_PSEUDO_MAP_FD};
+use ebpf::ffi::bpf_map_type::BPF_MAP_TYPE_XSKMAP;
+use ebpf::ffi::bpf_prog_type::BPF_PROG_TYPE_XDP;
+use ebpf::sys::{bpf_create_map, bpf_prog_load, bpf_update_elem, close};
+use ebpf::xdp::Socket;
+use libc::{pollfd, timeval, POLLIN};
+use log::{info, warn};
+use nell::{Family, Message, Netlink};
+use nell::ffi::{ifinfomsg, rtattr, AF_UNSPEC, IFLA_XDP, NLM_F_ACK, NLM_F_REQUEST, RTM_SETLINK};
+use nell::sys::Bytes;
+use pcap::{Packet, PacketHeader};
+use crate::os::ifindex;
+use super::{Config, Sample, Timestamp};
+use super::sample::FULL_SNAPLEN;
+
+const DESCS:   u32 = 4096;
+const FRAMESZ: u32 = 2048;
+
+pub struct Xdp {
+    index:   u32,
+    map:     c_int,
+    prog:    Option<c_int>,
+    socks:   Vec<Socket>,
+    free:    Vec<Vec<u64>>,
+    descs:   Vec<xdp_desc>,
+    snaplen: u32,
+}
+
+impl Xdp {
+    pub fn open(name: &str, dev: &str, cfg: &Config) -> Result<Self> {
+        if !cfg.mirror.is_match(name) {
+            return Err(anyhow!("AF_XDP consumes frames, {} is not a mirror", name));
+        }
+
+        let rate = match cfg.sample {
+            Sample::Rate(n)     => max(n, 1),
+            Sample::None        => 1,
+            Sample::Hash(_)     => return Err(anyhow!("hash sampling unsupported via AF_XDP")),
+            Sample::Adaptive(_) => return Err(anyhow!("adaptive sampling unsupported via AF_XDP")),
+        };
+
+        if cfg.filter.is_some() {
+            return Err(anyhow!("filter expressions unsupported via AF_XDP"));
+        }
+
+        let index  = ifindex(dev)?;
+        let queues = queues(dev)?;
+
+        let mut xdp = Self {
+            index:   index,
+            map:     xskmap(queues)?,
+            prog:    None,
+            socks:   Vec::with_capacity(queues as usize),
+            free:    Vec::with_capacity(queues as usize),
+            descs:   vec![xdp_desc::default(); DESCS as usize],
+            snaplen: max(cfg.snaplen, FULL_SNAPLEN) as u32,
+        };
+
+        for queue in 0..queues {
+            let mut sock = Socket::new(DESCS, DESCS, FRAMESZ, 0)?;
+
+            let mut frames = (0..DESCS).map(|n| (n * FRAMESZ) as u64).collect::<Vec<_>>();
+            let n = sock.fr.produce(&frames);
+            frames.drain(..n);
+
+            sock.bind(index, queue, 0)?;
+            insert(xdp.map, queue, sock.fd)?;
+
+            xdp.socks.push(sock);
+            xdp.free.push(frames);
+        }
+
+        let prog = program(xdp.map, rate)?;
+        match attach(index, prog) {
+            Ok(()) => xdp.prog = Some(prog),
+            Err(e) => {
+                let _ = close(prog);
+                return Err(e);
+            }
+        }
+
+        info!("capturing {} via AF_XDP on {} queues", name, queues);
+
+        Ok(xdp)
+    }
+
+    pub fn poll<F: FnMut(Packet<'_>) -> Result<()>>(&mut self, timeout: i32, mut f: F) -> Result<bool> {
+        let mut fds = self.socks.iter().map(|sock| pollfd {
+            fd:      sock.fd,
+            events:  POLLIN,
+            revents: 0,
+        }).collect::<Vec<_>>();
+
+        match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) } {
+            0  => return Ok(false),
+            -1 => return match Error::last_os_error() {
+                e if e.kind() == ErrorKind::Interrupted => Ok(false),
+                e                                       => Err(e.into()),
+            },
+            _  => (),
+        }
+
+        let ts = Timestamp::now();
+        let ts = timeval {
+            tv_sec:  ts.sec         as _,
+            tv_usec: (ts.nsec / 1000) as _,
+        };
+
+        let descs   = &mut self.descs;
+        let snaplen = self.snaplen;
+
+        for (sock, free) in self.socks.iter_mut().zip(&mut self.free) {
+            let n = sock.rx.consume(descs);
+
+            let result = descs[..n].iter().try_for_each(|desc| {
+                let caplen = min(desc.len, snaplen);
+                let header = PacketHeader {
+                    ts:     ts,
+                    caplen: caplen,
+                    len:    desc.len,
+                };
+
+                let data = unsafe {
+                    let ptr = sock.frames.add(desc.addr as usize);
+                    slice::from_raw_parts(ptr, caplen as usize)
+                };
+
+                f(Packet::new(&header, data))
+            });
+
+            // Return frames to the fill ring even when a packet failed,
+            // keeping any it has no room for until the next poll.
+            free.extend(descs[..n].iter().map(|desc| desc.addr));
+            let n = sock.fr.produce(free);
+            free.drain(..n);
+
+            result?;
+        }
+
+        Ok(true)
+    }
+
+    // Frames dropped by the kernel since the sockets were bound, when
+    // the rx rings were full.
+    pub fn drops(&self) -> Result<u64> {
+        let mut drops = 0;
+        for sock in &self.socks {
+            drops += sock.statistics()?.rx_dropped;
+        }
+        Ok(drops)
+    }
+}
+
+impl Drop for Xdp {
+    fn drop(&mut self) {
+        if let Some(prog) = self.prog {
+            if let Err(e) = attach(self.index, -1) {
+                warn!("error detaching XDP from {}: {}", self.index, e);
+            }
+            let _ = close(prog);
+        }
+
+        for sock in &self.socks {
+            let _ = close(sock.fd);
+            unsafe { libc::free(sock.frames as *mut c_void) };
+        }
+
+        let _ = close(self.map);
+    }
+}
+
+fn queues(dev: &str) -> Result<u32> {
+    let path  = format!("/sys/class/net/{}/queues", dev);
+    let count = fs::read_dir(path)?.filter(|entry| {
+        match entry {
+            Ok(entry) => entry.file_name().to_string_lossy().starts_with("rx-"),
+            Err(_)    => false,
+        }
+    }).count();
+    Ok(max(count as u32, 1))
+}
+
+fn xskmap(queues: u32) -> Result<c_int> {
+    Ok(bpf_create_map(&bpf_map_create_arg {
+        map_type:    BPF_MAP_TYPE_XSKMAP as u32,
+        key_size:    4,
+        val_size:    4,
+        max_entries: queues,
+        .. Default::default()
+    })?)
+}
+
+fn insert(map: c_int, queue: u32, fd: c_int) -> Result<()> {
+    let key = &queue as *const u32   as *const c_void;
+    let val = &fd    as *const c_int as *const c_void;
+    Ok(bpf_update_elem(map, key, val, 0)?)
+}
+
+// Redirect a 1:N random sample of frames to the AF_XDP socket bound to the
+// receive queue, everything else passes to the network stack. Redirected
+// frames are consumed by kappa, so Xdp::open only attaches to interfaces
+// marked as carrying mirrored traffic.
+fn program(map: c_int, rate: u32) -> Result<c_int> {
+    let code = [
+        insn(0xbf, 6, 1, 0, 0),                         // r6 = r1
+        insn(0x85, 0, 0, 0, 7),                         // r0 = get_prandom_u32()
+        insn(0x97, 0, 0, 0, rate as i32),               // r0 %= rate
+        insn(0x55, 0, 0, 6, 0),                         // if r0 != 0 goto pass
+        insn(0x61, 2, 6, 16, 0),                        // r2 = ctx->rx_queue_index
+        insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map),       // r1 = xskmap
+        insn(0x00, 0, 0, 0, 0),
+        insn(0xb7, 3, 0, 0, XDP_PASS),                  // r3 = XDP_PASS
+        insn(0x85, 0, 0, 0, 51),                        // r0 = redirect_map(r1, r2, r3)
+        insn(0x95, 0, 0, 0, 0),                         // return r0
+        insn(0xb7, 0, 0, 0, XDP_PASS),                  // pass: r0 = XDP_PASS
+        insn(0x95, 0, 0, 0, 0),                         // return r0
+    ];
+
+    let license = b"GPL\0";
+    let mut log = vec![0u8; 4096];
+
+    let arg = bpf_prog_load_arg {
+        prog_type: BPF_PROG_TYPE_XDP as u32,
+        insn_cnt:  code.len() as u32,
+        insns:     code.as_ptr()    as u64,
+        license:   license.as_ptr() as u64,
+        .. Default::default()
+    };
+
+    bpf_prog_load(&arg, &mut log).map_err(|e| anyhow!("XDP load failed: {}", e))
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> bpf_insn {
+    bpf_insn {
+        code: code,
+        regs: src << 4 | dst,
+        off:  off,
+        imm:  imm,
+    }
+}
+
+const XDP_PASS: i32 = 2;
+
+const IFLA_XDP_FD:    u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const NLA_F_NESTED:   u16 = 1 << 15;
+
+const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1;
+
+#[derive(Default)]
+#[repr(C)]
+pub struct xdpmsg {
+    pub info:   ifinfomsg,
+    pub xdp:    rtattr,
+    pub fd:     rtattr,
+    pub fdv:    i32,
+    pub flags:  rtattr,
+    pub flagsv: u32,
+}
+
+fn attach(index: u32, fd: c_int) -> Result<()> {
+    let mut sock = nell::sync::Socket::new(Family::ROUTE)?;
+
+    let flags = match fd {
+        -1 => 0,
+        _  => XDP_FLAGS_UPDATE_IF_NOEXIST,
+    };
+
+    let mut msg = Message::<xdpmsg>::new(RTM_SETLINK);
+    msg.set_flags(NLM_F_REQUEST | NLM_F_ACK);
+    msg.info.ifi_family  = AF_UNSPEC;
+    msg.info.ifi_index   = index as i32;
+    msg.xdp.rta_len      = 20;
+    msg.xdp.rta_type     = IFLA_XDP | NLA_F_NESTED;
+    msg.fd.rta_len       = 8;
+    msg.fd.rta_type      = IFLA_XDP_FD;
+    msg.fdv              = fd;
+    msg.flags.rta_len    = 8;
+    msg.flags.rta_type   = IFLA_XDP_FLAGS;
+    msg.flagsv           = flags;
+
+    sock.send(&msg)?;
+
+    match sock.recv::<()>()? {
+        Netlink::Ack => Ok(()),
+        _            => Err(anyhow!("unexpected XDP attach reply")),
+    }
+}
+
+unsafe impl Bytes for xdpmsg {}
@@ -8,50 +8,78 @@ use futures_util::stream::StreamExt;
 use log::{debug, warn};
 use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tokio_serde::{SymmetricallyFramed, formats::SymmetricalJson};
 use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
-use crate::capture::Flow;
+use crate::capture::{Flow, Link};
+use crate::dns::Cache;
 use crate::sockets::Sockets;
-use super::Record;
+use super::{Message, Record};
 
 pub struct Collect {
     node:  Option<Arc<String>>,
-    tx:    Sender<Vec<Record>>,
+    tx:    Sender<Message>,
     socks: Arc<Sockets>,
+    dns:   Arc<Cache>,
     dump:  Arc<AtomicBool>,
+    task:  JoinHandle<()>,
 }
 
 impl Collect {
-    pub fn new(agg: String, socks: Arc<Sockets>, rt: &Runtime, node: Option<String>) -> Self {
+    pub fn new(agg: String, socks: Arc<Sockets>, dns: Arc<Cache>, rt: &Runtime, node: Option<String>) -> Self {
         let dump = Arc::new(AtomicBool::new(false));
         let (tx, rx) = channel(1024);
-        rt.spawn(dispatch(agg, rx, dump.clone()));
+        let task = rt.spawn(dispatch(agg, rx, dump.clone()));
         Self {
             node:  node.map(Arc::new),
             tx:    tx,
             socks: socks,
+            dns:   dns,
             dump:  dump,
+            task:  task,
         }
     }
 
     pub fn collect(&mut self, flows: Vec<Flow>) -> Result<()> {
-        let records = self.socks.merge(flows, self.node.clone());
-        match self.tx.try_send(records) {
-            Ok(()) => (),
-            Err(e) => warn!("dispatch queue full: {:?}", e),
-        };
+        let mut records = self.socks.merge(flows, self.node.clone());
+        self.dns.merge(&mut records);
+        self.send(Message::Records(records));
+
+        let dns = self.dns.drain();
+        if !dns.is_empty() {
+            self.send(Message::Dns(dns));
+        }
+
         self.socks.compact();
+        self.dns.compact();
 
         Ok(())
     }
 
+    pub fn links(&mut self, links: Vec<Link>) {
+        self.send(Message::Links(self.node.clone(), links));
+    }
+
+    fn send(&mut self, msg: Message) {
+        match self.tx.try_send(msg) {
+            Ok(()) => (),
+            Err(e) => warn!("dispatch queue full: {:?}", e),
+        };
+    }
+
     pub fn dump(&self) -> Arc<AtomicBool> {
         self.dump.clone()
     }
+
+    pub fn finish(self, rt: &Runtime) -> Result<()> {
+        let Self { tx, task, .. } = self;
+        drop(tx);
+        Ok(rt.block_on(task)?)
+    }
 }
 
-async fn dispatch(agg: String, mut rx: Receiver<Vec<Record>>, dump: Arc<AtomicBool>) {
+async fn dispatch(agg: String, mut rx: Receiver<Message>, dump: Arc<AtomicBool>) {
     loop {
         let sock = connect(&agg).await;
 
@@ -62,13 +90,18 @@ async fn dispatch(agg: String, mut rx: Receiver<Vec<Record>>, dump: Arc<AtomicBo
 
         let mut codec = SymmetricallyFramed::new(framed, format);
 
-        while let Some(recs) = rx.next().await {
-            if dump.load(Ordering::SeqCst) {
+        loop {
+            let msg = match rx.next().await {
+                Some(msg) => msg,
+                None      => return,
+            };
+
+            if let (Message::Records(recs), true) = (&msg, dump.load(Ordering::SeqCst)) {
                 debug!("collect state:");
                 recs.iter().for_each(print)
             }
 
-            if let Err(e) = codec.send(recs).await {
+            if let Err(e) = codec.send(msg).await {
                 warn!("write error: {}", e);
                 break;
             }
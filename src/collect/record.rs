@@ -2,7 +2,8 @@ use std::sync::Arc;
 use std::time::Duration;
 use serde::{Serialize, Deserialize};
 use crate::augment::Object;
-use crate::capture::Flow;
+use crate::capture::{Flow, Link};
+use crate::dns::Response;
 use crate::sockets::Process;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,9 +14,29 @@ pub struct Record {
     pub srtt: Duration,
 }
 
+// Sent from agents to agg, which forwards their DNS responses along
+// with the flows and names the links each node captures.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    Records(Vec<Record>),
+    Dns(Vec<Response>),
+    Links(Option<Arc<String>>, Vec<Link>),
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Meta {
     pub proc: Option<Arc<Process>>,
     pub node: Option<Arc<String>>,
     pub kube: Option<Arc<Object>>,
+    pub name: Option<Arc<String>>,
+}
+
+impl Meta {
+    // Fill in whatever this side lacks from another view of it.
+    pub fn merge(&mut self, other: Meta) {
+        self.proc = self.proc.take().or(other.proc);
+        self.node = self.node.take().or(other.node);
+        self.kube = self.kube.take().or(other.kube);
+        self.name = self.name.take().or(other.name);
+    }
 }
@@ -1,4 +1,6 @@
+use std::cmp::{max, min};
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::mem;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -8,26 +10,33 @@ use log::debug;
 use parking_lot::Mutex;
 use kentik_api::{Client, Device};
 use crate::augment::Augment;
-use crate::capture::flow::{Addr, Key};
+use crate::capture::Link;
+use crate::capture::flow::{Addr, Biflow, Key};
 use crate::collect::{Meta, Record};
-use crate::export::{pack, send};
+use crate::dns::{Cache, Response};
+use crate::export::{pack, send, send_dns, send_interfaces};
 use crate::sockets::Process;
 
 pub struct Combine {
-    queue:   Mutex<HashMap<Key, Record>>,
-    empty:   Mutex<HashMap<Key, Record>>,
+    queue:   Mutex<HashMap<(Key, Node), Record>>,
+    empty:   Mutex<HashMap<(Key, Node), Record>>,
     source:  Mutex<HashMap<Addr, Source>>,
     client:  Arc<Client>,
     device:  Arc<Device>,
     dump:    AtomicBool,
     augment: Arc<Augment>,
+    dns:     Cache,
+    links:   Mutex<HashMap<Node, Vec<Link>>>,
     timeout: Duration,
 }
 
+type Node = Option<Arc<String>>;
+
 #[derive(Debug)]
 pub struct Source {
     node: Option<Arc<String>>,
     proc: Option<Arc<Process>>,
+    name: Option<Arc<String>>,
     seen: Instant,
 }
 
@@ -41,6 +50,8 @@ impl Combine {
             device:  Arc::new(device),
             dump:    AtomicBool::new(false),
             augment: augment,
+            dns:     Cache::new(true),
+            links:   Mutex::new(HashMap::new()),
             timeout: Duration::from_secs(60),
         }
     }
@@ -50,25 +61,60 @@ impl Combine {
         let mut source = self.source.lock();
 
         let now = Instant::now();
-        let def = || Source { node: None, proc: None, seen: now };
+        let def = || Source { node: None, proc: None, name: None, seen: now };
 
         let mut update = |addr: Addr, meta: &Meta| {
             let entry = source.entry(addr).or_insert_with(def);
             meta.node.as_ref().map(|node| entry.node = Some(node.clone()));
             meta.proc.as_ref().map(|proc| entry.proc = Some(proc.clone()));
+            meta.name.as_ref().map(|name| entry.name = Some(name.clone()));
             entry.seen = now;
         };
 
         for r in rs {
             update(r.flow.src, &r.src);
             update(r.flow.dst, &r.dst);
-            queue.entry(r.flow.key()).and_modify(|entry| {
-                entry.flow.bytes   += r.flow.bytes;
-                entry.flow.packets += r.flow.packets;
+
+            // Hash sampled flows are the same packets wherever they were
+            // observed, keep each node's view apart until export. Agents
+            // require a node identifier when hash sampling.
+            let node = match r.flow.hashed {
+                true  => r.src.node.clone(),
+                false => None,
+            };
+
+            let key = (r.flow.key(), node);
+            if r.flow.biflow != Biflow::Off && !queue.contains_key(&key) {
+                if let Some(entry) = queue.get_mut(&(key.0.reverse(), key.1.clone())) {
+                    let mut flow = r.flow;
+                    flow.flip();
+                    entry.flow.merge(&flow);
+                    continue;
+                }
+            }
+
+            queue.entry(key).and_modify(|entry| {
+                entry.flow.merge(&r.flow);
             }).or_insert(r);
         }
     }
 
+    pub fn dns(&self, rs: Vec<Response>) {
+        rs.into_iter().for_each(|r| self.dns.record(r));
+    }
+
+    // Each agent reports every link it captures, upload the union so
+    // one agent's update does not drop another's links.
+    pub fn links(&self, node: Node, ls: Vec<Link>) {
+        let mut links = self.links.lock();
+        links.insert(node, ls);
+
+        let all    = links.values().flatten().cloned().collect();
+        let client = self.client.clone();
+        let device = self.device.clone();
+        tokio::spawn(send_interfaces(client, device, all));
+    }
+
     pub fn export(&self) -> Result<()> {
         let mut queue  = self.queue.lock();
         let mut export = self.empty.lock();
@@ -82,6 +128,7 @@ impl Combine {
                 Meta {
                     node: s.node.clone(),
                     proc: s.proc.clone(),
+                    name: s.name.clone(),
                     ..Default::default()
                 }
             }).unwrap_or_default()
@@ -101,7 +148,7 @@ impl Combine {
             export.iter().for_each(print)
         }
 
-        let mut rs = export.drain().map(|(_, r)| r).collect::<Vec<_>>();
+        let mut rs = dedup(export.drain());
 
         self.augment.merge(&mut rs);
 
@@ -112,6 +159,13 @@ impl Combine {
             tokio::spawn(send(client, device, msg));
         }
 
+        let dns = self.dns.drain();
+        if !dns.is_empty() {
+            let client = self.client.clone();
+            let device = self.device.clone();
+            tokio::spawn(send_dns(client, device, dns));
+        }
+
         Ok(())
     }
 
@@ -121,7 +175,53 @@ impl Combine {
     }
 }
 
-fn print<'a>((key, rec): (&'a Key, &'a Record)) {
+// Hash sampled flows reported by several nodes describe the same
+// packets, export the record from the node that saw the most of them
+// along with what the other nodes knew of its endpoints. Biflows may be
+// oriented differently per node so endpoints are compared in canonical
+// order.
+pub fn dedup(rs: impl Iterator<Item = ((Key, Node), Record)>) -> Vec<Record> {
+    let mut map = HashMap::new();
+
+    for ((key, _), r) in rs {
+        let key = match (r.flow.biflow, key) {
+            (Biflow::Off, key)        => key,
+            (_, Key(proto, src, dst)) => Key(proto, min(src, dst), max(src, dst)),
+        };
+
+        match map.entry(key) {
+            Entry::Occupied(mut e) if count(&r) > count(e.get()) => {
+                let loser = e.insert(r);
+                absorb(e.get_mut(), loser);
+            },
+            Entry::Occupied(mut e) => {
+                absorb(e.get_mut(), r);
+            },
+            Entry::Vacant(e) => {
+                e.insert(r);
+            },
+        }
+    }
+
+    map.into_iter().map(|(_, r)| r).collect()
+}
+
+// Merge the endpoint metadata of a dropped record into the exported
+// one, matching endpoints by address since orientations may differ.
+fn absorb(r: &mut Record, other: Record) {
+    let (src, dst) = match r.flow.src == other.flow.src {
+        true  => (other.src, other.dst),
+        false => (other.dst, other.src),
+    };
+    r.src.merge(src);
+    r.dst.merge(dst);
+}
+
+fn count(r: &Record) -> usize {
+    r.flow.packets + r.flow.reverse.packets
+}
+
+fn print<'a>(((key, _), rec): (&'a (Key, Node), &'a Record)) {
     let src = rec.src.proc.as_ref().map(|p| p.comm.as_str()).unwrap_or("??");
     let dst = rec.dst.proc.as_ref().map(|p| p.comm.as_str()).unwrap_or("??");
     debug!("{}:{} -> {}:{}: {} -> {}",
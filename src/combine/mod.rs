@@ -1,3 +1,6 @@
 pub use combine::Combine;
 
 mod combine;
+
+#[cfg(test)]
+mod test;
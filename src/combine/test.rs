@@ -0,0 +1,77 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::capture::{Addr, Flow, Key, Protocol};
+use crate::capture::flow::Biflow;
+use crate::collect::{Meta, Record};
+use crate::sockets::Process;
+use super::combine::dedup;
+
+#[test]
+fn dedup_hashed() {
+    let a = addr([10, 0, 0, 1], 50000);
+    let b = addr([10, 0, 0, 2], 80);
+
+    // Node x saw most of the flow and knows the client, node y saw it
+    // reversed and knows the server.
+    let mut x = record("x", a, b, 10, Biflow::Canonical);
+    x.src.proc = Some(process("curl"));
+
+    let mut y = record("y", b, a, 4, Biflow::Canonical);
+    y.src.proc = Some(process("nginx"));
+    y.src.name = Some(Arc::new("web".to_owned()));
+
+    let rs = dedup(vec![entry(y), entry(x)].into_iter());
+    assert_eq!(rs.len(), 1);
+
+    let r = &rs[0];
+    assert_eq!(r.flow.packets, 10);
+    assert_eq!(r.src.node.as_deref().map(String::as_str), Some("x"));
+    assert_eq!(r.src.proc.as_ref().map(|p| p.comm.as_str()), Some("curl"));
+    assert_eq!(r.dst.proc.as_ref().map(|p| p.comm.as_str()), Some("nginx"));
+    assert_eq!(r.dst.name.as_deref().map(String::as_str), Some("web"));
+
+    // Unidirectional flows in opposite directions are distinct.
+    let x = record("x", a, b, 10, Biflow::Off);
+    let y = record("y", b, a, 4, Biflow::Off);
+    assert_eq!(dedup(vec![entry(x), entry(y)].into_iter()).len(), 2);
+}
+
+fn entry(r: Record) -> ((Key, Option<Arc<String>>), Record) {
+    ((r.flow.key(), r.src.node.clone()), r)
+}
+
+fn record(node: &str, src: Addr, dst: Addr, packets: usize, biflow: Biflow) -> Record {
+    let node = Some(Arc::new(node.to_owned()));
+    Record {
+        flow: Flow {
+            protocol: Protocol::TCP,
+            src:      src,
+            dst:      dst,
+            packets:  packets,
+            hashed:   true,
+            biflow:   biflow,
+            ..Default::default()
+        },
+        src:  Meta { node: node.clone(), ..Default::default() },
+        dst:  Meta { node: node,         ..Default::default() },
+        srtt: Duration::from_secs(0),
+    }
+}
+
+fn process(comm: &str) -> Arc<Process> {
+    Arc::new(Process {
+        comm:      comm.to_owned(),
+        cmdline:   Vec::new(),
+        cgroups:   Vec::new(),
+        pid:       1,
+        container: None,
+    })
+}
+
+fn addr(ip: [u8; 4], port: u16) -> Addr {
+    Addr {
+        addr: IpAddr::from(ip),
+        port: port,
+    }
+}
@@ -0,0 +1,82 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::mem;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use crate::collect::Record;
+use super::{Data, Response};
+
+pub struct Cache {
+    names:   Mutex<HashMap<IpAddr, Entry>>,
+    pending: Mutex<Vec<Response>>,
+    forward: bool,
+}
+
+struct Entry {
+    name:    Arc<String>,
+    expires: Instant,
+}
+
+impl Cache {
+    pub fn new(forward: bool) -> Self {
+        Self {
+            names:   Mutex::new(HashMap::new()),
+            pending: Mutex::new(Vec::new()),
+            forward: forward,
+        }
+    }
+
+    // Map each address in the response to the queried name, so flows to
+    // a CDN edge are tagged with the name the client asked for rather
+    // than the end of the CNAME chain.
+    pub fn record(&self, r: Response) {
+        let now  = Instant::now();
+        let name = Arc::new(r.question.clone());
+
+        let mut names = self.names.lock();
+        for answer in &r.answers {
+            if let Data::Addr(addr) = answer.data {
+                let ttl = max(Duration::from_secs(answer.ttl as u64), MIN_TTL);
+                names.insert(addr, Entry {
+                    name:    name.clone(),
+                    expires: now + ttl,
+                });
+            }
+        }
+        drop(names);
+
+        if self.forward {
+            let mut pending = self.pending.lock();
+            if pending.len() < MAX_PENDING {
+                pending.push(r);
+            }
+        }
+    }
+
+    pub fn merge(&self, rs: &mut [Record]) {
+        let names = self.names.lock();
+
+        let lookup = |addr: IpAddr| {
+            names.get(&addr).map(|e| e.name.clone())
+        };
+
+        for r in rs {
+            r.src.name = lookup(r.flow.src.addr);
+            r.dst.name = lookup(r.flow.dst.addr);
+        }
+    }
+
+    pub fn drain(&self) -> Vec<Response> {
+        mem::take(&mut *self.pending.lock())
+    }
+
+    pub fn compact(&self) {
+        let now = Instant::now();
+        self.names.lock().retain(|_, e| e.expires > now);
+    }
+}
+
+const MIN_TTL:     Duration = Duration::from_secs(300);
+const MAX_PENDING: usize    = 16384;
@@ -0,0 +1,5 @@
+pub use cache::Cache;
+pub use parse::{parse, Answer, Data, Response};
+
+pub mod cache;
+pub mod parse;
@@ -0,0 +1,127 @@
+use std::convert::{TryFrom, TryInto};
+use std::net::IpAddr;
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub question: String,
+    pub host:     IpAddr,
+    pub answers:  Vec<Answer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Answer {
+    pub name: String,
+    pub data: Data,
+    pub ttl:  u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Data {
+    Addr(IpAddr),
+    CNAME(String),
+}
+
+// Parse a successful DNS response sent to host, keeping the A, AAAA, and
+// CNAME answers that fit in the captured bytes.
+pub fn parse(host: IpAddr, msg: &[u8]) -> Option<Response> {
+    let flags   = u16(msg, 2)?;
+    let qdcount = u16(msg, 4)?;
+    let ancount = u16(msg, 6)?;
+
+    if flags & QR == 0 || flags & OPCODE != 0 || flags & RCODE != 0 || qdcount != 1 {
+        return None;
+    }
+
+    let (question, n) = name(msg, 12)?;
+    let mut n = n + 4;
+
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        match answer(msg, n) {
+            Some((Some(answer), next)) => { answers.push(answer); n = next },
+            Some((None,         next)) => n = next,
+            None                       => break,
+        }
+    }
+
+    match answers.is_empty() {
+        true  => None,
+        false => Some(Response {
+            question: question,
+            host:     host,
+            answers:  answers,
+        }),
+    }
+}
+
+fn answer(msg: &[u8], n: usize) -> Option<(Option<Answer>, usize)> {
+    let (name, n) = name(msg, n)?;
+    let kind  = u16(msg, n)?;
+    let ttl   = u32::from_be_bytes(msg.get(n+4..n+8)?.try_into().ok()?);
+    let len   = u16(msg, n+8)? as usize;
+    let rdata = n + 10;
+    let data  = msg.get(rdata..rdata+len)?;
+
+    let data = match (kind, data.len()) {
+        (A,    4)  => Data::Addr(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+        (AAAA, 16) => Data::Addr(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+        (CNAME, _) => Data::CNAME(self::name(msg, rdata)?.0),
+        _          => return Some((None, rdata + len)),
+    };
+
+    Some((Some(Answer {
+        name: name,
+        data: data,
+        ttl:  ttl,
+    }), rdata + len))
+}
+
+// Decode a possibly compressed name, returning it along with the offset
+// following the name at its original position.
+fn name(msg: &[u8], mut n: usize) -> Option<(String, usize)> {
+    let mut name  = String::new();
+    let mut next  = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *msg.get(n)? as usize;
+
+        match len & 0xC0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = msg.get(n+1..n+1+len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(label.iter().map(|b| b.to_ascii_lowercase() as char));
+                n += 1 + len;
+            },
+            0xC0 => {
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return None;
+                }
+                next = next.or(Some(n + 2));
+                n = (u16(msg, n)? & 0x3FFF) as usize;
+            },
+            _ => return None,
+        }
+    }
+
+    Some((name, next.unwrap_or(n + 1)))
+}
+
+fn u16(msg: &[u8], n: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(n..n+2)?.try_into().ok()?))
+}
+
+const QR:     u16 = 0x8000;
+const OPCODE: u16 = 0x7800;
+const RCODE:  u16 = 0x000F;
+
+const A:     u16 = 1;
+const CNAME: u16 = 5;
+const AAAA:  u16 = 28;
+
+const MAX_JUMPS: usize = 16;
@@ -1,6 +1,11 @@
 use capnp::struct_list::Builder;
 use crate::chf_capnp::custom::{Owned, value};
 
+pub enum Value<'a> {
+    U32(u32),
+    Str(&'a str),
+}
+
 pub struct Customs<'a> {
     builder: Builder<'a, Owned>,
     index:   u32,
@@ -20,4 +25,11 @@ impl<'a> Customs<'a> {
         f(&mut custom.init_value());
         self.index += 1;
     }
+
+    pub fn value(&mut self, id: u32, value: &Value) {
+        self.next(id, |v| match value {
+            Value::U32(n) => v.set_uint32_val(*n),
+            Value::Str(s) => v.set_str_val(s),
+        });
+    }
 }
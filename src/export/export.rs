@@ -1,21 +1,27 @@
+use std::mem;
 use std::sync::Arc;
 use anyhow::Result;
+use futures::future::{self, FutureExt};
 use log::debug;
 use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
 use kentik_api::{Client, Device};
-use crate::capture::Flow;
+use crate::capture::{Flow, Link};
+use crate::dns::Cache;
 use crate::sockets::{Event, Sockets};
-use super::{get_or_create_device, pack, send};
+use super::{get_or_create_device, pack, send, send_dns, send_interfaces};
 
 pub struct Export {
     client: Arc<Client>,
     device: Arc<Device>,
     rt:     Runtime,
     socks:  Arc<Sockets>,
+    dns:    Arc<Cache>,
+    tasks:  Vec<JoinHandle<()>>,
 }
 
 impl Export {
-    pub fn new(client: Client, device: &str, plan: Option<u64>, socks: Arc<Sockets>) -> Result<Self> {
+    pub fn new(client: Client, device: &str, plan: Option<u64>, socks: Arc<Sockets>, dns: Arc<Cache>) -> Result<Self> {
         let rt     = Runtime::new()?;
         let client = Arc::new(client);
         let device = rt.block_on(get_or_create_device(client.clone(), &device, plan))?;
@@ -24,25 +30,60 @@ impl Export {
             device: Arc::new(device),
             rt:     rt,
             socks:  socks,
+            dns:    dns,
+            tasks:  Vec::new(),
         })
     }
 
     pub fn export(&mut self, flows: Vec<Flow>, node: Option<Arc<String>>) -> Result<()> {
         debug!("exporting {} flows", flows.len());
-        let rs = self.socks.merge(flows, node);
+        let mut rs = self.socks.merge(flows, node);
+        self.dns.merge(&mut rs);
+
+        // Drop finished sends so the list only holds those in flight.
+        let tasks  = mem::take(&mut self.tasks);
+        self.tasks = tasks.into_iter().filter_map(|mut task| {
+            match (&mut task).now_or_never() {
+                Some(_) => None,
+                None    => Some(task),
+            }
+        }).collect();
 
         for chunk in rs.chunks(16384) {
             let msg = pack(&self.device, chunk)?;
             let client = self.client.clone();
             let device = self.device.clone();
-            self.rt.spawn(send(client, device, msg));
+            self.tasks.push(self.rt.spawn(send(client, device, msg)));
+        }
+
+        let dns = self.dns.drain();
+        if !dns.is_empty() {
+            let client = self.client.clone();
+            let device = self.device.clone();
+            self.tasks.push(self.rt.spawn(send_dns(client, device, dns)));
         }
 
         self.socks.compact();
+        self.dns.compact();
 
         Ok(())
     }
 
+    // Name the device interfaces so exported ports are readable.
+    pub fn interfaces(&mut self, links: Vec<Link>) {
+        let client = self.client.clone();
+        let device = self.device.clone();
+        self.tasks.push(self.rt.spawn(send_interfaces(client, device, links)));
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let Self { rt, tasks, .. } = self;
+        for result in rt.block_on(future::join_all(tasks)) {
+            result?;
+        }
+        Ok(())
+    }
+
     pub fn record(&mut self, e: Event) {
         self.socks.update(e);
     }
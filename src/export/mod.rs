@@ -1,7 +1,10 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use anyhow::Result;
 use log::{debug, warn};
-use kentik_api::{Client, Device};
+use kentik_api::{Client, Device, DnsAnswer, DnsQuestion, DnsResponse, Interface};
+use crate::capture::Link;
+use crate::dns::{Data, Response};
 use kentik_api::Error::*;
 
 pub async fn send(client: Arc<Client>, device: Arc<Device>, msg: Vec<u8>) {
@@ -11,6 +14,62 @@ pub async fn send(client: Arc<Client>, device: Arc<Device>, msg: Vec<u8>) {
     }
 }
 
+pub async fn send_dns(client: Arc<Client>, device: Arc<Device>, rs: Vec<Response>) {
+    let dns = rs.into_iter().map(dns).collect::<Vec<_>>();
+    match client.dns(&device, &dns).await {
+        Ok(()) => (),
+        Err(e) => warn!("failed to deliver dns: {:?}", e),
+    }
+}
+
+pub async fn send_interfaces(client: Arc<Client>, device: Arc<Device>, links: Vec<Link>) {
+    let interfaces = links.into_iter().map(interface).collect::<HashMap<_, _>>();
+    match client.update_interfaces(&device, &interfaces).await {
+        Ok(()) => (),
+        Err(e) => warn!("failed to update interfaces: {:?}", e),
+    }
+}
+
+fn dns(r: Response) -> DnsResponse {
+    let answers = r.answers.into_iter().map(|a| {
+        let (cname, ip) = match a.data {
+            Data::Addr(ip)     => (None, Some(ip)),
+            Data::CNAME(cname) => (Some(cname), None),
+        };
+
+        DnsAnswer {
+            name:  a.name,
+            cname: cname,
+            ip:    ip,
+            ttl:   a.ttl,
+        }
+    }).collect();
+
+    DnsResponse {
+        question: DnsQuestion {
+            name: r.question,
+            host: r.host,
+        },
+        answers:  answers,
+    }
+}
+
+// Veths are captured within their peer's namespace, describe them by
+// the peer's ifindex as well.
+fn interface(link: Link) -> (String, Interface) {
+    let desc = match link.peer {
+        Some(peer) => format!("{} peer ifindex {}", link.name, peer),
+        None       => link.name.clone(),
+    };
+
+    (link.name.clone(), Interface {
+        index: link.index as u64,
+        alias: link.name,
+        desc:  desc,
+        ..Default::default()
+    })
+}
+
 pub async fn get_or_create_device(client: Arc<Client>, name: &str, plan: Option<u64>) -> Result<Device> {
     let device = match client.get_device_by_name(name).await {
         Ok(device)       => device,
@@ -18,6 +77,15 @@ pub async fn get_or_create_device(client: Arc<Client>, name: &str, plan: Option<
         Err(e)           => Err(e)?,
     };
     debug!("device {:?}", device);
+
+    let missing = pack::OPTIONAL.iter().filter(|name| {
+        !device.customs.iter().any(|c| c.name == **name)
+    }).collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        warn!("device {} lacks custom columns {:?}, their values will not be exported", name, missing);
+    }
+
     Ok(device)
 }
 
@@ -42,3 +110,6 @@ mod column;
 mod custom;
 mod export;
 mod pack;
+
+#[cfg(test)]
+mod test;
@@ -5,22 +5,33 @@ use log::{log_enabled, trace, Level::Trace};
 use pnet::{packet::PrimitiveValues, util::MacAddr};
 use kentik_api::Device;
 use crate::chf_capnp::*;
-use crate::capture::{Direction, Protocol};
+use crate::capture::{Direction, Flow, Protocol, Timestamp};
 use crate::collect::Record;
+use crate::packet::Mpls;
 use super::column::Columns;
-use super::custom::Customs;
+use super::custom::{Customs, Value};
+
+// Columns only exported when the device has them, checked once when
+// the device is set up.
+pub const OPTIONAL: &[&str] = &[
+    "INT03", "INT04", "INT05", "INT06", "INT07", "INT08", "INT09", "INT10",
+    "INT11", "INT12", "INT13", "INT14", "INT15", "INT16", "INT17", "INT18",
+    "STR22", "STR23", "STR24", "STR25", "STR26", "STR27", "STR28", "STR29",
+    "STR30",
+];
 
 pub fn pack(device: &Device, records: &[Record]) -> Result<Vec<u8>> {
+    let optional = |name: &str| {
+        device.customs.iter().find(|c| c.name == name).map(|c| c.id as u32)
+    };
+
     let column = |name: &str| {
-        match device.customs.iter().find(|c| c.name == name) {
-            Some(c) => Ok(c.id as u32),
-            None    => Err(anyhow!("missing custom column '{}'", name)),
-        }
+        optional(name).ok_or_else(|| anyhow!("missing custom column '{}'", name))
     };
 
     let lat   = column("APPL_LATENCY_MS")?;
     let app   = column("APP_PROTOCOL")?;
-    let _nt00 = column("INT00")?;
+    let int00 = column("INT00")?;
     let int01 = column("INT01")?;
     let int02 = column("INT02")?;
     let str00 = column("STR00")?;
@@ -45,6 +56,28 @@ pub fn pack(device: &Device, records: &[Record]) -> Result<Vec<u8>> {
     let str19 = column("STR19")?;
     let str20 = column("STR20")?;
     let str21 = column("STR21")?;
+    let int03 = optional("INT03");
+    let int04 = optional("INT04");
+    let int05 = optional("INT05");
+    let int06 = optional("INT06");
+    let int07 = optional("INT07");
+    let str22 = optional("STR22");
+    let str23 = optional("STR23");
+    let str24 = optional("STR24");
+    let str25 = optional("STR25");
+    let str26 = optional("STR26");
+    let str27 = optional("STR27");
+    let str28 = optional("STR28");
+    let int08 = optional("INT08");
+    let int09 = optional("INT09");
+    let int10 = optional("INT10");
+    let int17 = optional("INT17");
+    let int18 = optional("INT18");
+    let str29 = optional("STR29");
+    let str30 = optional("STR30");
+    let bins  = ["INT11", "INT12", "INT13", "INT14", "INT15", "INT16"].iter().map(|name| {
+        optional(name)
+    }).collect::<Vec<_>>();
 
     let mut msg  = Builder::new_default();
     let root = msg.init_root::<packed_c_h_f::Builder>();
@@ -76,27 +109,55 @@ pub fn pack(device: &Device, records: &[Record]) -> Result<Vec<u8>> {
             IpAddr::V6(ip) => msg.set_ipv6_dst_addr(&ip.octets()),
         };
 
+        msg.set_timestamp_nano(nanos(flow.start));
+        msg.set_timestamp(flow.end.sec as i64);
+        msg.set_sampled_packet_size(size(flow));
+        msg.set_ip_size(size(flow));
         msg.set_l4_src_port(flow.src.port as u32);
         msg.set_l4_dst_port(flow.dst.port as u32);
         msg.set_tos(flow.tos as u32);
         msg.set_tcp_flags(flow.tcp_flags() as u32);
+        msg.set_tcp_retransmit(flow.retransmits);
         msg.set_sample_rate(flow.sample);
+        msg.set_mpls_type(mpls_type(&flow.mpls));
 
+        // Forwarded traffic is reported once, as ingress on the link
+        // it was captured on.
         match flow.direction {
-            Direction::In => {
+            Direction::In | Direction::Transit => {
                 msg.set_in_pkts(flow.packets as u64);
                 msg.set_in_bytes(flow.bytes as u64);
-                msg.set_input_port(dst_eth_mac as u32 & 0xFFFF);
-                msg.set_vlan_in(flow.ethernet.vlan.unwrap_or(0) as u32);
+                msg.set_out_pkts(flow.reverse.packets as u64);
+                msg.set_out_bytes(flow.reverse.bytes as u64);
+                msg.set_input_port(flow.index);
+                msg.set_vlan_in(flow.ethernet.vlans.first().unwrap_or(0) as u32);
             },
             Direction::Out | Direction::Unknown => {
                 msg.set_out_pkts(flow.packets as u64);
                 msg.set_out_bytes(flow.bytes as u64);
-                msg.set_output_port(dst_eth_mac as u32 & 0xFFFF);
-                msg.set_vlan_out(flow.ethernet.vlan.unwrap_or(0) as u32);
+                msg.set_in_pkts(flow.reverse.packets as u64);
+                msg.set_in_bytes(flow.reverse.bytes as u64);
+                msg.set_output_port(flow.index);
+                msg.set_vlan_out(flow.ethernet.vlans.first().unwrap_or(0) as u32);
             }
         };
 
+        let tls  = flow.tls.as_deref();
+        let http = flow.http.as_deref();
+        let tsrc = flow.tunnel.map(|t| t.src.to_string());
+        let tdst = flow.tunnel.map(|t| t.dst.to_string());
+        let strs = [
+            (str22, src.name.as_deref().map(String::as_str)),
+            (str23, dst.name.as_deref().map(String::as_str)),
+            (str24, tls.and_then(|tls| tls.sni.as_deref())),
+            (str25, tls.and_then(|tls| tls.alpn.as_deref())),
+            (str26, http.and_then(|http| http.method.as_deref())),
+            (str27, http.and_then(|http| http.host.as_deref())),
+            (str28, http.and_then(|http| http.path.as_deref())),
+            (str29, tsrc.as_deref()),
+            (str30, tdst.as_deref()),
+        ];
+
         let src = Columns::new(src);
         let dst = Columns::new(dst);
 
@@ -110,12 +171,70 @@ pub fn pack(device: &Device, records: &[Record]) -> Result<Vec<u8>> {
         count += src.count();
         count += dst.count();
 
+        let mut extra = Vec::new();
+
+        if flow.fragments > 0 {
+            extra.push((int00, Value::U32(flow.fragments as u32)));
+        }
+
+        if let (Some(id), &[_, .., inner]) = (int03, flow.ethernet.vlans.as_slice()) {
+            extra.push((id, Value::U32(inner as u32)));
+        }
+
+        for (id, label) in [int04, int05, int06].iter().zip(flow.mpls.labels.as_slice()) {
+            if let Some(id) = *id {
+                extra.push((id, Value::U32(*label)));
+            }
+        }
+
+        if let Some(id) = int07 {
+            let duration = (flow.end - flow.start).num_milliseconds();
+            extra.push((id, Value::U32(duration as u32)));
+        }
+
+        if let (Some(id), Some(status)) = (int08, http.and_then(|http| http.status)) {
+            extra.push((id, Value::U32(status as u32)));
+        }
+
+        if let Some(tunnel) = flow.tunnel {
+            let ids = [(int17, tunnel.encap.id()), (int18, tunnel.id)];
+            for (id, n) in ids.iter().copied() {
+                if let Some(id) = id {
+                    extra.push((id, Value::U32(n)));
+                }
+            }
+        }
+
+        let sizes = [(int09, flow.sizes.min), (int10, flow.sizes.max)];
+        let hist  = bins.iter().copied().zip(flow.sizes.hist.iter().copied());
+        for (id, n) in sizes.iter().copied().chain(hist) {
+            if let (Some(id), true) = (id, n > 0) {
+                extra.push((id, Value::U32(n)));
+            }
+        }
+
+        for (id, value) in &strs {
+            if let (Some(id), Some(value)) = (id, value) {
+                extra.push((*id, Value::Str(value)));
+            }
+        }
+
+        count += extra.len() as u32;
+
         let mut customs = Customs::new(msg.init_custom(count));
 
+        let srtt = match srtt.is_zero() {
+            true  => flow.rtt.total(),
+            false => *srtt,
+        };
         let srtt = srtt.as_millis() as u32;
-        customs.next(app, |v| v.set_uint32_val(1));
+        customs.next(app, |v| v.set_uint32_val(flow.app.id()));
         customs.next(lat, |v| v.set_uint32_val(srtt));
 
+        for (id, value) in &extra {
+            customs.value(*id, value);
+        }
+
         if let Some(proc) = src.proc {
             customs.next(int01, |v| v.set_uint32_val(proc.pid));
             customs.next(str00, |v| v.set_str_val(proc.comm));
@@ -184,6 +303,26 @@ pub fn pack(device: &Device, records: &[Record]) -> Result<Vec<u8>> {
     Ok(vec)
 }
 
+// CHF reports the MPLS ethertype, 0x8847 for unicast and 0x8848 for
+// multicast, and zero for unlabelled traffic.
+fn mpls_type(mpls: &Mpls) -> u32 {
+    match mpls.labels.first() {
+        Some(_) => mpls.ethertype as u32,
+        None    => 0,
+    }
+}
+
+// Mean size of the sampled packets in either direction.
+fn size(flow: &Flow) -> u32 {
+    let bytes   = flow.bytes + flow.reverse.bytes;
+    let packets = flow.packets + flow.reverse.packets;
+    bytes.checked_div(packets).unwrap_or(0) as u32
+}
+
+fn nanos(ts: Timestamp) -> i64 {
+    (ts.sec * 1_000_000_000 + ts.nsec) as i64
+}
+
 fn pack_mac(mac: &MacAddr) -> u64 {
     let prims = mac.to_primitive_values();
     (prims.0 as u64) << 40 |
@@ -0,0 +1,141 @@
+use std::time::Duration;
+use anyhow::Result;
+use capnp::message::{Reader, ReaderOptions};
+use capnp::serialize::OwnedSegments;
+use capnp::serialize_packed;
+use kentik_api::{Column, Device};
+use crate::capture::{Direction, Flow, Timestamp};
+use crate::chf_capnp::{c_h_f, packed_c_h_f};
+use crate::chf_capnp::custom::value::Which;
+use crate::collect::{Meta, Record};
+use crate::packet::Mpls;
+use super::pack::OPTIONAL;
+
+#[test]
+fn mpls_type() -> Result<()> {
+    let mut mpls = Mpls::default();
+    mpls.labels.push(100);
+    mpls.ethertype = 0x8848;
+
+    let msg  = pack(vec![Flow { mpls, ..Default::default() }, Flow::default()])?;
+    let msgs = msg.get_root::<packed_c_h_f::Reader>()?.get_msgs()?;
+
+    assert_eq!(msgs.get(0).get_mpls_type(), 0x8848);
+    assert_eq!(msgs.get(1).get_mpls_type(), 0);
+    assert_eq!(custom(&msgs.get(0), "INT04"), Some(100));
+
+    Ok(())
+}
+
+#[test]
+fn timestamps() -> Result<()> {
+    let flow = Flow {
+        start: Timestamp { sec: 10, nsec: 250_000_000 },
+        end:   Timestamp { sec: 12, nsec: 750_000_000 },
+        ..Default::default()
+    };
+
+    let msg  = pack(vec![flow])?;
+    let msgs = msg.get_root::<packed_c_h_f::Reader>()?.get_msgs()?;
+
+    assert_eq!(msgs.get(0).get_timestamp_nano(), 10_250_000_000);
+    assert_eq!(msgs.get(0).get_timestamp(), 12);
+
+    Ok(())
+}
+
+#[test]
+fn sizes() -> Result<()> {
+    let mut flow = Flow {
+        packets: 3,
+        bytes:   1800,
+        ..Default::default()
+    };
+    flow.reverse.packets = 1;
+    flow.reverse.bytes   = 200;
+    for &size in &[64, 236, 1500] {
+        flow.sizes.record(size);
+    }
+
+    let msg  = pack(vec![flow, Flow::default()])?;
+    let msgs = msg.get_root::<packed_c_h_f::Reader>()?.get_msgs()?;
+
+    assert_eq!(msgs.get(0).get_sampled_packet_size(), 500);
+    assert_eq!(custom(&msgs.get(0), "INT09"), Some(64));
+    assert_eq!(custom(&msgs.get(0), "INT10"), Some(1500));
+    assert_eq!(custom(&msgs.get(0), "INT11"), Some(1));
+    assert_eq!(custom(&msgs.get(0), "INT12"), Some(1));
+    assert_eq!(custom(&msgs.get(0), "INT15"), Some(1));
+    assert_eq!(custom(&msgs.get(0), "INT16"), None);
+
+    // Flows without packets have no size.
+    assert_eq!(msgs.get(1).get_sampled_packet_size(), 0);
+    assert_eq!(custom(&msgs.get(1), "INT09"), None);
+
+    Ok(())
+}
+
+#[test]
+fn ports() -> Result<()> {
+    let flow = |direction| Flow {
+        index:     7,
+        direction: direction,
+        ..Default::default()
+    };
+
+    let dirs = [Direction::In, Direction::Transit, Direction::Out, Direction::Unknown];
+    let msg  = pack(dirs.iter().copied().map(flow).collect())?;
+    let msgs = msg.get_root::<packed_c_h_f::Reader>()?.get_msgs()?;
+
+    let ports = msgs.iter().map(|m| (m.get_input_port(), m.get_output_port())).collect::<Vec<_>>();
+    assert_eq!(ports, vec![(7, 0), (7, 0), (0, 7), (0, 7)]);
+
+    Ok(())
+}
+
+fn pack(flows: Vec<Flow>) -> Result<Reader<OwnedSegments>> {
+    let records = flows.into_iter().map(|flow| Record {
+        flow: flow,
+        src:  Meta::default(),
+        dst:  Meta::default(),
+        srtt: Duration::default(),
+    }).collect::<Vec<_>>();
+
+    let vec = super::pack(&device(), &records)?;
+    Ok(serialize_packed::read_message(&mut &vec[80..], ReaderOptions::new())?)
+}
+
+fn custom(msg: &c_h_f::Reader, name: &str) -> Option<u32> {
+    let id = id(name);
+    msg.get_custom().ok()?.iter().find(|c| c.get_id() == id).and_then(|c| {
+        match c.get_value().which() {
+            Ok(Which::Uint32Val(n)) => Some(n),
+            _                       => None,
+        }
+    })
+}
+
+fn device() -> Device {
+    let customs = COLUMNS.iter().chain(OPTIONAL).map(|name| Column {
+        id:   id(name) as u64,
+        name: name.to_string(),
+        ..Default::default()
+    }).collect();
+
+    Device {
+        customs: customs,
+        ..Default::default()
+    }
+}
+
+fn id(name: &str) -> u32 {
+    let index = COLUMNS.iter().chain(OPTIONAL).position(|n| *n == name);
+    index.map_or(0, |n| n as u32 + 1)
+}
+
+const COLUMNS: &[&str] = &[
+    "APPL_LATENCY_MS", "APP_PROTOCOL", "INT00", "INT01", "INT02",
+    "STR00", "STR01", "STR02", "STR03", "STR04", "STR05", "STR06", "STR07",
+    "STR08", "STR09", "STR10", "STR11", "STR12", "STR13", "STR14", "STR15",
+    "STR16", "STR17", "STR18", "STR19", "STR20", "STR21",
+];
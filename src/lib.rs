@@ -1,12 +1,14 @@
 pub mod agent;
 pub mod agg;
 pub mod probe;
+pub mod replay;
 
 pub mod args;
 pub mod augment;
 pub mod capture;
 pub mod collect;
 pub mod combine;
+pub mod dns;
 pub mod export;
 pub mod link;
 pub mod os;
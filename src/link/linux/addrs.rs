@@ -0,0 +1,20 @@
+use anyhow::Result;
+use nell::{Message, Netlink};
+use nell::api::{addr, Addr};
+use nell::ffi::*;
+use nell::sync::Socket;
+
+pub fn addrs(sock: &mut Socket) -> Result<Vec<Addr>> {
+    let mut msg = Message::<rtgenmsg>::new(RTM_GETADDR);
+    msg.set_flags(NLM_F_REQUEST | NLM_F_DUMP);
+    msg.rtgen_family = AF_UNSPEC;
+    sock.send(&msg)?;
+
+    let mut addrs = Vec::new();
+
+    while let Netlink::Msg(msg) = sock.recv::<ifaddrmsg>()? {
+        addrs.push(addr(&msg)?);
+    }
+
+    Ok(addrs)
+}
@@ -1,8 +1,10 @@
-pub use monitor::Links;
+pub use monitor::{Links, locals};
+pub use addrs::addrs;
 pub use links::{Link, links, link};
 pub use peer::{Peer, peer};
 pub use crate::os::{findns, getns, setns};
 
+mod addrs;
 mod links;
 mod monitor;
 mod peer;
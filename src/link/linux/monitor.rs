@@ -1,33 +1,46 @@
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 use anyhow::Result;
 use crossbeam_channel::{Sender, Receiver, TryRecvError, unbounded};
 use log::{debug, error};
-use nell::{Family, Netlink};
-use nell::api::Any;
+use nell::{Error, Family, Netlink};
+use nell::api::{addr, Addr, Any};
 use nell::ffi::*;
 use nell::sync::Socket;
-use crate::link::{Add, Event};
+use crate::link::{Add, Event, Locals};
 use super::Link;
-use super::{link, links, peer};
+use super::{addrs, link, links, peer};
 use TryRecvError::*;
 
 const IFF_UP:      u32 = nell::ffi::IFF_UP      as u32;
 const IFF_PROMISC: u32 = nell::ffi::IFF_PROMISC as u32;
 
+const WATCH_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct Links {
-    rx: Receiver<Event>,
+    rx:     Receiver<Event>,
+    locals: Locals,
 }
 
 impl Links {
     pub fn watch(shutdown: Arc<AtomicBool>) -> Result<Self> {
         let (tx, rx) = unbounded();
-        thread::spawn(move || match monitor(tx, shutdown) {
+        let locals   = Locals::default();
+        let addrs    = locals.clone();
+        thread::spawn(move || match monitor(tx, addrs, shutdown) {
             Ok(_)  => debug!("link monitor finished"),
             Err(e) => error!("link monitor failed: {:?}", e),
         });
-        Ok(Self { rx })
+        Ok(Self { rx, locals })
+    }
+
+    pub fn locals(&self) -> Locals {
+        self.locals.clone()
     }
 
     pub fn recv(&mut self) -> Result<Option<Event>> {
@@ -39,9 +52,13 @@ impl Links {
     }
 }
 
-fn monitor(tx: Sender<Event>, shutdown: Arc<AtomicBool>) -> Result<()> {
+fn monitor(tx: Sender<Event>, locals: Locals, shutdown: Arc<AtomicBool>) -> Result<()> {
     let mut sock = Socket::new(Family::ROUTE)?;
 
+    for addr in addrs(&mut sock)? {
+        locals.insert(addr.addr);
+    }
+
     for link in links(&mut sock)? {
         if link.flags & IFF_UP > 0 {
             tx.send(add(link))?;
@@ -50,21 +67,25 @@ fn monitor(tx: Sender<Event>, shutdown: Arc<AtomicBool>) -> Result<()> {
 
     while !shutdown.load(Ordering::Acquire) {
         let mut sock = Socket::new(Family::ROUTE)?;
-        sock.bind(0, RTMGRP_LINK)?;
+        sock.bind(0, RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR)?;
 
         while let Netlink::Msg(msg) = sock.recv::<()>()? {
             if shutdown.load(Ordering::Acquire) {
                 break;
             }
 
-            if let Any::IFInfo(msg) = msg.any() {
-                let link = link(&msg)?;
-                let up = link.flags & IFF_UP > 0 && msg.ifi_change & IFF_PROMISC == 0;
-                match msg.nlmsg_type() {
-                    RTM_NEWLINK if up => tx.send(add(link))?,
-                    RTM_DELLINK       => tx.send(del(link))?,
-                    _                 => ()
-                }
+            match msg.any() {
+                Any::IFInfo(msg) => {
+                    let link = link(&msg)?;
+                    let up = link.flags & IFF_UP > 0 && msg.ifi_change & IFF_PROMISC == 0;
+                    match msg.nlmsg_type() {
+                        RTM_NEWLINK if up => tx.send(add(link))?,
+                        RTM_DELLINK       => tx.send(del(link))?,
+                        _                 => ()
+                    }
+                },
+                Any::IFAddr(msg) => update(&locals, msg.nlmsg_type(), addr(&msg)?),
+                _                => (),
             }
         }
     }
@@ -72,15 +93,85 @@ fn monitor(tx: Sender<Event>, shutdown: Arc<AtomicBool>) -> Result<()> {
     Ok(())
 }
 
+// Track the local addresses of the calling thread's network namespace,
+// the watching thread inherits that namespace and exits, releasing it,
+// once shutdown is set.
+pub fn locals(shutdown: Arc<AtomicBool>) -> Result<Locals> {
+    let locals = Locals::default();
+
+    let sock = Socket::new(Family::ROUTE)?;
+    sock.bind(0, RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR)?;
+    timeout(&sock, WATCH_TIMEOUT)?;
+
+    for addr in addrs(&mut Socket::new(Family::ROUTE)?)? {
+        locals.insert(addr.addr);
+    }
+
+    let addrs = locals.clone();
+    thread::spawn(move || match watch(sock, addrs, shutdown) {
+        Ok(_)  => debug!("address monitor finished"),
+        Err(e) => error!("address monitor failed: {:?}", e),
+    });
+
+    Ok(locals)
+}
+
+fn watch(mut sock: Socket, locals: Locals, shutdown: Arc<AtomicBool>) -> Result<()> {
+    while !shutdown.load(Ordering::Acquire) {
+        let msg = match sock.recv::<()>() {
+            Ok(Netlink::Msg(msg))         => msg,
+            Ok(_)                         => break,
+            Err(Error::IO(e)) if idle(&e) => continue,
+            Err(e)                        => return Err(e.into()),
+        };
+
+        if let Any::IFAddr(msg) = msg.any() {
+            update(&locals, msg.nlmsg_type(), addr(&msg)?);
+        }
+    }
+    Ok(())
+}
+
+fn timeout(sock: &Socket, timeout: Duration) -> Result<()> {
+    let tv = libc::timeval {
+        tv_sec:  timeout.as_secs() as _,
+        tv_usec: timeout.subsec_micros() as _,
+    };
+
+    let rc = unsafe {
+        let ptr = &tv as *const libc::timeval as *const libc::c_void;
+        let len = size_of::<libc::timeval>() as libc::socklen_t;
+        libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO, ptr, len)
+    };
+
+    match rc {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        _  => Ok(()),
+    }
+}
+
+fn idle(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted)
+}
+
+fn update(locals: &Locals, kind: u16, addr: Addr) {
+    match kind {
+        RTM_NEWADDR => locals.insert(addr.addr),
+        RTM_DELADDR => locals.remove(&addr.addr),
+        _           => (),
+    }
+}
+
 fn add(link: Link) -> Event {
-    let name = link.name.clone();
-    let peer = link.peer.map(peer);
-    let (dev, mac, netns) = match peer.transpose() {
-        Ok(Some((netns, link))) => (link.name, link.addr, Some(netns)),
-        Ok(None)                => (link.name, link.addr, None),
+    let name  = link.name.clone();
+    let index = link.index;
+    let peer  = link.peer.map(peer);
+    let (peer, dev, mac, netns) = match peer.transpose() {
+        Ok(Some((netns, link))) => (Some(link.index), link.name, link.addr, Some(netns)),
+        Ok(None)                => (None, link.name, link.addr, None),
         Err(e)                  => return Event::Error(name, e.into()),
     };
-    Event::Add(Add { name, dev, mac, netns })
+    Event::Add(Add { name, index, peer, dev, mac, netns })
 }
 
 fn del(Link { name, .. }: Link) -> Event {
@@ -0,0 +1,33 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use parking_lot::RwLock;
+
+#[derive(Clone, Debug, Default)]
+pub struct Locals {
+    addrs: Arc<RwLock<HashSet<IpAddr>>>,
+}
+
+impl Locals {
+    pub fn insert(&self, addr: IpAddr) {
+        self.addrs.write().insert(addr);
+    }
+
+    pub fn remove(&self, addr: &IpAddr) {
+        self.addrs.write().remove(addr);
+    }
+
+    pub fn replace(&self, addrs: HashSet<IpAddr>) {
+        *self.addrs.write() = addrs;
+    }
+
+    // Which of the two addresses are local, or None while no addresses
+    // are known yet.
+    pub fn lookup(&self, src: &IpAddr, dst: &IpAddr) -> Option<(bool, bool)> {
+        let addrs = self.addrs.read();
+        match addrs.is_empty() {
+            true  => None,
+            false => Some((addrs.contains(src), addrs.contains(dst))),
+        }
+    }
+}
@@ -12,12 +12,17 @@ pub enum Event {
 #[derive(Debug)]
 pub struct Add {
     pub name:  String,
+    pub index: u32,
+    pub peer:  Option<u32>,
     pub dev:   String,
     pub mac:   Option<MacAddr>,
     pub netns: Option<File>,
 }
 
-pub use monitor::Links;
+pub use locals::Locals;
+pub use monitor::{Links, locals};
+
+mod locals;
 
 #[cfg(target_os = "linux")]
 #[path = "linux/mod.rs"]
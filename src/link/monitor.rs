@@ -9,21 +9,29 @@ use log::{debug, error};
 use pcap::Device;
 use pnet::datalink;
 use pnet::util::MacAddr;
-use super::{Add, Event};
+use crate::os::ifindex;
+use super::{Add, Event, Locals};
 use TryRecvError::*;
 
 pub struct Links {
-    rx: Receiver<Event>,
+    rx:     Receiver<Event>,
+    locals: Locals,
 }
 
 impl Links {
     pub fn watch(shutdown: Arc<AtomicBool>) -> Result<Self> {
         let (tx, rx) = unbounded();
-        thread::spawn(move || match monitor(tx, shutdown) {
+        let locals   = Locals::default();
+        let addrs    = locals.clone();
+        thread::spawn(move || match monitor(tx, addrs, shutdown) {
             Ok(_)  => debug!("link monitor finished"),
             Err(e) => error!("link monitor failed: {:?}", e),
         });
-        Ok(Self { rx })
+        Ok(Self { rx, locals })
+    }
+
+    pub fn locals(&self) -> Locals {
+        self.locals.clone()
     }
 
     pub fn recv(&mut self) -> Result<Option<Event>> {
@@ -35,12 +43,18 @@ impl Links {
     }
 }
 
-fn monitor(tx: Sender<Event>, shutdown: Arc<AtomicBool>) -> Result<()> {
+fn monitor(tx: Sender<Event>, locals: Locals, shutdown: Arc<AtomicBool>) -> Result<()> {
     let mut links = HashSet::new();
 
     while !shutdown.load(Ordering::Acquire) {
-        let macs = datalink::interfaces().into_iter().map(|link| {
-            (link.name, link.mac)
+        let ifaces = datalink::interfaces();
+
+        locals.replace(ifaces.iter().flat_map(|link| {
+            link.ips.iter().map(|net| net.ip())
+        }).collect());
+
+        let macs = ifaces.into_iter().map(|link| {
+            (link.name, (link.index, link.mac))
         }).collect::<HashMap<_, _>>();
 
         let curr = Device::list()?.into_iter().map(|d| {
@@ -50,8 +64,18 @@ fn monitor(tx: Sender<Event>, shutdown: Arc<AtomicBool>) -> Result<()> {
         let copy = links.clone();
 
         for link in curr.difference(&copy) {
-            let mac = macs.get(link).and_then(Option::clone);
-            tx.send(add(link, mac))?;
+            let (index, mac) = match macs.get(link).cloned() {
+                Some(entry) => entry,
+                None        => match ifindex(link) {
+                    Ok(index) => (index, None),
+                    Err(e)    => {
+                        tx.send(Event::Error(link.to_string(), e))?;
+                        links.insert(link.to_string());
+                        continue;
+                    }
+                },
+            };
+            tx.send(add(link, index, mac))?;
             links.insert(link.to_string());
         }
 
@@ -65,11 +89,17 @@ fn monitor(tx: Sender<Event>, shutdown: Arc<AtomicBool>) -> Result<()> {
     Ok(())
 }
 
-fn add(link: &str, mac: Option<MacAddr>) -> Event {
+fn add(link: &str, index: u32, mac: Option<MacAddr>) -> Event {
     Event::Add(Add {
         name:  link.to_string(),
+        index: index,
+        peer:  None,
         dev:   link.to_string(),
         mac:   mac,
         netns: None,
     })
 }
+
+pub fn locals(_shutdown: Arc<AtomicBool>) -> Result<Locals> {
+    Ok(Locals::default())
+}
@@ -6,7 +6,7 @@ use env_logger::Builder;
 use jemallocator::Jemalloc;
 use log::info;
 use log::LevelFilter::*;
-use kappa::{agent, agg, probe};
+use kappa::{agent, agg, probe, replay};
 
 #[global_allocator]
 static ALLOC: Jemalloc = Jemalloc;
@@ -27,10 +27,11 @@ fn main() -> Result<()> {
     info!("initializing kappa {}", ver);
 
     match args.subcommand() {
-        ("agent", Some(args)) => agent::agent(&args),
-        ("agg",   Some(args)) => agg::agg(&args),
-        ("probe", Some(args)) => probe::probe(&args),
-        _                     => unreachable!(),
+        ("agent",  Some(args)) => agent::agent(&args),
+        ("agg",    Some(args)) => agg::agg(&args),
+        ("probe",  Some(args)) => probe::probe(&args),
+        ("replay", Some(args)) => replay::replay(&args),
+        _                      => unreachable!(),
     }.unwrap_or_else(abort);
 
     Ok(())
@@ -1,9 +1,21 @@
+use std::ffi::CString;
+use std::io::Error;
+use anyhow::Result;
+
 pub fn getpid() -> u32 {
     unsafe {
         libc::getpid() as u32
     }
 }
 
+pub fn ifindex(name: &str) -> Result<u32> {
+    let name = CString::new(name)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0     => Err(Error::last_os_error().into()),
+        index => Ok(index),
+    }
+}
+
 pub use os::{findns, getns, setns};
 
 #[cfg(target_os = "linux")]
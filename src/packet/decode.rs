@@ -1,46 +1,219 @@
-use pnet::packet::{Packet as PacketExt, PacketSize};
-use pnet::packet::ip::IpNextHeaderProtocols;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use pnet::packet::PacketSize;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::udp::UdpPacket;
 use pnet::packet::vlan::VlanPacket;
 use pnet::packet::ethernet::{EthernetPacket, EtherType, EtherTypes};
-use super::{Packet, Opaque};
-
-pub fn decode<'a>(p: &'a EthernetPacket<'a>) -> (Option<u16>, Option<Packet<'a>>) {
-    let mut ethertype = p.get_ethertype();
-    let mut payload   = p.payload();
-    let mut vlan      = None;
-
-    while ethertype == EtherTypes::Vlan {
-        if let Some(pkt) = VlanPacket::new(payload) {
-            vlan      = Some(pkt.get_vlan_identifier());
-            ethertype = pkt.get_ethertype();
-            payload   = &payload[pkt.packet_size()..];
-        } else {
-            return (None, None)
+use super::{Encap, Mpls, Packet, Opaque, Tunnel, Vlans};
+use super::packet::extensions;
+
+const TEB: EtherType = EtherType(0x6558);
+
+pub struct Frame<'a> {
+    pub vlans:  Vlans,
+    pub mpls:   Mpls,
+    pub tunnel: Option<Tunnel>,
+    pub outer:  usize,
+    pub packet: Option<Packet<'a>>,
+}
+
+pub fn decode(ethertype: EtherType, payload: &[u8]) -> Frame<'_> {
+    let mut frame = Frame {
+        vlans:  Vlans::default(),
+        mpls:   Mpls::default(),
+        tunnel: None,
+        outer:  0,
+        packet: None,
+    };
+    frame.packet = ethernet(&mut frame, ethertype, payload);
+
+    // Length of the outer headers stripped by decapsulation.
+    if let (Some(_), Some(pkt)) = (&frame.tunnel, &frame.packet) {
+        frame.outer = pkt.data().as_ptr() as usize - payload.as_ptr() as usize;
+    }
+
+    frame
+}
+
+fn ethernet<'a>(frame: &mut Frame, mut ethertype: EtherType, mut payload: &'a [u8]) -> Option<Packet<'a>> {
+    while let EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ = ethertype {
+        let pkt = VlanPacket::new(payload)?;
+
+        if frame.tunnel.is_none() {
+            frame.vlans.push(pkt.get_vlan_identifier());
         }
+
+        ethertype = pkt.get_ethertype();
+        payload   = &payload[pkt.packet_size()..];
     }
 
     match ethertype {
-        EtherTypes::Ipv4 => (vlan, ipv4(payload)),
-        EtherTypes::Ipv6 => (vlan, ipv6(payload)),
-        _                => (vlan, other(payload, ethertype)),
+        EtherTypes::Ipv4      => ipv4(frame, payload),
+        EtherTypes::Ipv6      => ipv6(frame, payload),
+        EtherTypes::Mpls      => mpls(frame, ethertype, payload),
+        EtherTypes::MplsMcast => mpls(frame, ethertype, payload),
+        TEB                   => bridged(frame, payload),
+        _                     => other(payload, ethertype),
     }
 }
 
-fn ipv4(payload: &[u8]) -> Option<Packet> {
-    let mut pkt = Ipv4Packet::new(payload)?;
-    if pkt.get_next_level_protocol() == IpNextHeaderProtocols::Ipv4 {
-        let n = pkt.get_header_length() as usize * 4;
-        pkt = Ipv4Packet::new(&payload[n..])?;
+fn mpls<'a>(frame: &mut Frame, ethertype: EtherType, mut payload: &'a [u8]) -> Option<Packet<'a>> {
+    let record = frame.tunnel.is_none();
+
+    if record {
+        frame.mpls.ethertype = ethertype.0;
+    }
+
+    loop {
+        let entry = u32::from_be_bytes(payload.get(..4)?.try_into().ok()?);
+
+        if record {
+            frame.mpls.labels.push(entry >> 12);
+        }
+
+        payload = &payload[4..];
+
+        if entry & MPLS_BOTTOM != 0 {
+            break;
+        }
+    }
+
+    match payload.first()? >> 4 {
+        4 => ipv4(frame, payload),
+        6 => ipv6(frame, payload),
+        _ => other(payload, ethertype),
+    }
+}
+
+fn bridged<'a>(frame: &mut Frame, payload: &'a [u8]) -> Option<Packet<'a>> {
+    let eth = EthernetPacket::new(payload)?;
+    let n   = EthernetPacket::minimum_packet_size();
+    ethernet(frame, eth.get_ethertype(), &payload[n..])
+}
+
+fn ipv4<'a>(frame: &mut Frame, payload: &'a [u8]) -> Option<Packet<'a>> {
+    let pkt = Ipv4Packet::new(payload)?;
+
+    if frame.tunnel.is_none() && pkt.get_fragment_offset() == 0 {
+        let src  = IpAddr::V4(pkt.get_source());
+        let dst  = IpAddr::V4(pkt.get_destination());
+        let next = pkt.get_next_level_protocol();
+        let n    = pkt.get_header_length() as usize * 4;
+
+        if let Some(inner) = payload.get(n..).and_then(|p| decap(frame, next, src, dst, p)) {
+            return Some(inner);
+        }
     }
+
     Some(Packet::IPv4(pkt))
 }
 
-fn ipv6(payload: &[u8])-> Option<Packet> {
-    Some(Packet::IPv6(Ipv6Packet::new(payload)?))
+fn ipv6<'a>(frame: &mut Frame, payload: &'a [u8]) -> Option<Packet<'a>> {
+    let pkt = Ipv6Packet::new(payload)?;
+
+    if frame.tunnel.is_none() {
+        let src  = IpAddr::V6(pkt.get_source());
+        let dst  = IpAddr::V6(pkt.get_destination());
+        let n    = Ipv6Packet::minimum_packet_size();
+        let next = extensions(pkt.get_next_header(), &payload[n..]);
+
+        if let (next, rest, None) = next {
+            if let Some(inner) = decap(frame, next, src, dst, rest) {
+                return Some(inner);
+            }
+        }
+    }
+
+    Some(Packet::IPv6(pkt))
 }
 
 fn other(payload: &[u8], ethertype: EtherType)-> Option<Packet> {
     Opaque::new(ethertype.0, payload).map(Packet::Other)
 }
+
+fn decap<'a>(frame: &mut Frame, next: IpNextHeaderProtocol, src: IpAddr, dst: IpAddr, payload: &'a [u8]) -> Option<Packet<'a>> {
+    let (encap, id, ethertype, payload) = match next {
+        IpNextHeaderProtocols::Ipv4 => (Encap::IPIP, 0, EtherTypes::Ipv4, payload),
+        IpNextHeaderProtocols::Ipv6 => (Encap::IPIP, 0, EtherTypes::Ipv6, payload),
+        IpNextHeaderProtocols::Gre  => gre(payload)?,
+        IpNextHeaderProtocols::Udp  => udp(payload)?,
+        _                           => return None,
+    };
+
+    frame.tunnel = Some(Tunnel {
+        encap: encap,
+        src:   src,
+        dst:   dst,
+        id:    id,
+    });
+
+    // Only IP payloads replace the outer packet, anything else is
+    // accounted to the tunnel flow itself.
+    match ethernet(frame, ethertype, payload) {
+        Some(Packet::Other(..)) | None => {
+            frame.tunnel = None;
+            None
+        },
+        inner => inner,
+    }
+}
+
+fn gre(payload: &[u8]) -> Option<(Encap, u32, EtherType, &[u8])> {
+    let (flags, ethertype) = match payload {
+        &[a, b, c, d, ..] => (u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d])),
+        _                 => return None,
+    };
+
+    if flags & GRE_VERSION != 0 {
+        return None;
+    }
+
+    let mut n = 4;
+
+    if flags & GRE_CSUM != 0 {
+        n += 4;
+    }
+
+    let key = match flags & GRE_KEY {
+        0 => 0,
+        _ => {
+            let key = payload.get(n..n+4)?.try_into().ok()?;
+            n += 4;
+            u32::from_be_bytes(key)
+        }
+    };
+
+    if flags & GRE_SEQ != 0 {
+        n += 4;
+    }
+
+    Some((Encap::GRE, key, EtherType(ethertype), payload.get(n..)?))
+}
+
+fn udp(payload: &[u8]) -> Option<(Encap, u32, EtherType, &[u8])> {
+    let udp  = UdpPacket::new(payload)?;
+    let data = &payload[UdpPacket::minimum_packet_size()..];
+
+    match (udp.get_destination(), data) {
+        (4789 | 8472, &[flags, _, _, _, a, b, c, _, ..]) if flags & 0x08 != 0 => {
+            let vni = u32::from_be_bytes([0, a, b, c]);
+            Some((Encap::VXLAN, vni, TEB, &data[8..]))
+        },
+        (6081, &[vo, _, p, q, a, b, c, _, ..]) if vo >> 6 == 0 => {
+            let vni = u32::from_be_bytes([0, a, b, c]);
+            let n   = 8 + (vo & 0x3F) as usize * 4;
+            Some((Encap::Geneve, vni, EtherType(u16::from_be_bytes([p, q])), data.get(n..)?))
+        },
+        _ => None,
+    }
+}
+
+const GRE_CSUM:    u16 = 0x8000;
+const GRE_KEY:     u16 = 0x2000;
+const GRE_SEQ:     u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+const MPLS_BOTTOM: u32 = 0x0100;
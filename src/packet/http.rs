@@ -0,0 +1,79 @@
+use std::str;
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Http {
+    pub method: Option<String>,
+    pub host:   Option<String>,
+    pub path:   Option<String>,
+    pub status: Option<u16>,
+}
+
+impl Http {
+    // Combine the request seen in one direction with the response seen
+    // in the other, preferring the newer values.
+    pub fn merge(&self, other: &Http) -> Http {
+        Http {
+            method: other.method.clone().or_else(|| self.method.clone()),
+            host:   other.host.clone().or_else(|| self.host.clone()),
+            path:   other.path.clone().or_else(|| self.path.clone()),
+            status: other.status.or(self.status),
+        }
+    }
+}
+
+// Parse the request line and Host header, or the response status line,
+// at the start of a TCP payload.
+pub fn http(payload: &[u8]) -> Option<Http> {
+    let end  = payload.windows(2).position(|w| w == b"\r\n").unwrap_or(payload.len());
+    let line = str::from_utf8(&payload[..end]).ok()?;
+
+    let mut split = line.splitn(3, ' ');
+    let first  = split.next()?;
+    let second = split.next()?;
+
+    if first.starts_with("HTTP/1.") {
+        let status = second.parse().ok()?;
+        return Some(Http {
+            status: Some(status),
+            ..Default::default()
+        });
+    }
+
+    if !METHODS.contains(&first) || !split.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    Some(Http {
+        method: Some(first.to_owned()),
+        host:   host(&payload[end..]),
+        path:   Some(prefix(second)),
+        status: None,
+    })
+}
+
+fn host(headers: &[u8]) -> Option<String> {
+    let end = headers.iter().rposition(|&b| b == b'\n')?;
+    headers[..end].split(|&b| b == b'\n').find_map(|line| {
+        let line = str::from_utf8(line).ok()?.trim_end_matches('\r');
+        let (name, value) = line.split_at(line.find(':')?);
+        match name.eq_ignore_ascii_case("host") {
+            true  => Some(value[1..].trim().to_ascii_lowercase()),
+            false => None,
+        }
+    })
+}
+
+// Keep the first few path segments without the query string to bound
+// the number of distinct values.
+fn prefix(target: &str) -> String {
+    let path = target.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    let end  = path.match_indices('/').nth(PATH_SEGMENTS).map(|(n, _)| n);
+    path[..end.unwrap_or(path.len())].to_owned()
+}
+
+const METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "HEAD", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+const PATH_SEGMENTS: usize = 2;
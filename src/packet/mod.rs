@@ -1,5 +1,9 @@
 mod decode;
+mod http;
 mod packet;
+mod tls;
 
-pub use decode::decode;
-pub use packet::{Packet, Opaque, Transport};
+pub use decode::{decode, Frame};
+pub use packet::{Packet, Encap, Fragment, Mpls, Opaque, Stack, Transport, Tunnel, Vlans};
+pub use http::{http, Http};
+pub use tls::{client_hello, Tls};
@@ -1,12 +1,13 @@
 use std::cmp::min;
 use std::net::{IpAddr, Ipv4Addr};
 use pnet::packet::{Packet as PacketExt};
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
+use serde::{Serialize, Deserialize};
 
 #[derive(Debug)]
 pub enum Packet<'a> {
@@ -23,6 +24,43 @@ pub enum Transport<'a> {
     Other(Opaque<'a>),
 }
 
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Fragment {
+    pub id:     u32,
+    pub offset: u16,
+    pub more:   bool,
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Stack<T> {
+    items: [T; 4],
+    len:   u8,
+}
+
+pub type Vlans = Stack<u16>;
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Mpls {
+    pub labels:    Stack<u32>,
+    pub ethertype: u16,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Tunnel {
+    pub encap: Encap,
+    pub src:   IpAddr,
+    pub dst:   IpAddr,
+    pub id:    u32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Encap {
+    IPIP,
+    GRE,
+    VXLAN,
+    Geneve,
+}
+
 #[derive(Debug)]
 pub struct Opaque<'a> {
     pub protocol: u16,
@@ -62,6 +100,14 @@ impl<'a> Packet<'a> {
         }
     }
 
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Packet::IPv4(ref p)  => p.packet(),
+            Packet::IPv6(ref p)  => p.packet(),
+            Packet::Other(ref o) => o.payload,
+        }
+    }
+
     pub fn payload(&self) -> &[u8] {
         match self {
             Packet::IPv4(ref p)  => p.payload(),
@@ -70,10 +116,39 @@ impl<'a> Packet<'a> {
         }
     }
 
+    pub fn transport_len(&self) -> usize {
+        match self {
+            Packet::IPv4(ref ip) => {
+                let n = ip.get_header_length() as usize * 4;
+                (ip.get_total_length() as usize).saturating_sub(n)
+            },
+            Packet::IPv6(ref ip) => {
+                let payload = ip.payload();
+                let (_, rest, _) = extensions(ip.get_next_header(), payload);
+                let n = payload.len() - rest.len();
+                (ip.get_payload_length() as usize).saturating_sub(n)
+            },
+            Packet::Other(ref o) => o.payload.len(),
+        }
+    }
+
     pub fn transport<'n>(&self, p: &'n [u8]) -> Option<Transport<'n>> {
+        let (next, payload, frag) = match self {
+            Packet::IPv4(ref ip) => (ip.get_next_level_protocol(), ip.payload_slice(p), fragment(ip)),
+            Packet::IPv6(ref ip) => extensions(ip.get_next_header(), ip.payload_slice(p)),
+            Packet::Other(..)    => return None,
+        };
+
+        match frag {
+            Some(f) if f.offset > 0 => Opaque::new(next.0, payload).map(Transport::Other),
+            _                       => self.next(next, payload),
+        }
+    }
+
+    pub fn fragment(&self) -> Option<Fragment> {
         match self {
-            Packet::IPv4(ref ip) => self.next(ip.get_next_level_protocol(), ip.payload_slice(p)),
-            Packet::IPv6(ref ip) => self.next(ip.get_next_header(),         ip.payload_slice(p)),
+            Packet::IPv4(ref ip) => fragment(ip),
+            Packet::IPv6(ref ip) => extensions(ip.get_next_header(), ip.payload()).2,
             Packet::Other(..)    => None,
         }
     }
@@ -88,6 +163,34 @@ impl<'a> Packet<'a> {
     }
 }
 
+impl Encap {
+    pub fn id(self) -> u32 {
+        match self {
+            Encap::IPIP   => 1,
+            Encap::GRE    => 2,
+            Encap::VXLAN  => 3,
+            Encap::Geneve => 4,
+        }
+    }
+}
+
+impl<T: Copy> Stack<T> {
+    pub fn push(&mut self, item: T) {
+        if let Some(slot) = self.items.get_mut(self.len as usize) {
+            *slot = item;
+            self.len += 1;
+        }
+    }
+
+    pub fn first(&self) -> Option<T> {
+        self.as_slice().first().copied()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.items[..self.len as usize]
+    }
+}
+
 impl<'a> Opaque<'a> {
     pub fn new<T: Into<u16>>(protocol: T, payload: &'a [u8]) -> Option<Opaque<'a>> {
         Some(Opaque {
@@ -97,6 +200,59 @@ impl<'a> Opaque<'a> {
     }
 }
 
+fn fragment(ip: &Ipv4Packet) -> Option<Fragment> {
+    let more   = ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+    let offset = ip.get_fragment_offset() << 3;
+
+    match more || offset > 0 {
+        true  => Some(Fragment {
+            id:     ip.get_identification() as u32,
+            offset: offset,
+            more:   more,
+        }),
+        false => None,
+    }
+}
+
+// Walk the IPv6 extension header chain to the upper-layer protocol. A
+// truncated chain stops at the last complete header.
+pub fn extensions(mut next: IpNextHeaderProtocol, mut payload: &[u8]) -> (IpNextHeaderProtocol, &[u8], Option<Fragment>) {
+    let mut frag = None;
+
+    loop {
+        let n = match (next, payload) {
+            (IpNextHeaderProtocols::Hopopt,    &[_, len, ..]) => (len as usize + 1) * 8,
+            (IpNextHeaderProtocols::Ipv6Route, &[_, len, ..]) => (len as usize + 1) * 8,
+            (IpNextHeaderProtocols::Ipv6Opts,  &[_, len, ..]) => (len as usize + 1) * 8,
+            (IpNextHeaderProtocols::Ah,        &[_, len, ..]) => (len as usize + 2) * 4,
+            (IpNextHeaderProtocols::Ipv6Frag,  &[_, _, a, b, c, d, e, f, ..]) => {
+                let off = u16::from_be_bytes([a, b]);
+                frag = Some(Fragment {
+                    id:     u32::from_be_bytes([c, d, e, f]),
+                    offset: off & !0x7,
+                    more:   off & 0x1 == 0x1,
+                });
+                8
+            },
+            _ => break,
+        };
+
+        if payload.len() < n {
+            break;
+        }
+
+        next    = IpNextHeaderProtocol::new(payload[0]);
+        payload = &payload[n..];
+
+        // What follows a non-first fragment header is fragment data.
+        if matches!(frag, Some(Fragment { offset, .. }) if offset > 0) {
+            break;
+        }
+    }
+
+    (next, payload, frag)
+}
+
 trait PayloadSlice {
     fn payload_slice<'p>(&self, p: &'p [u8]) -> &'p [u8];
 }
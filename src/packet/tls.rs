@@ -0,0 +1,83 @@
+use std::convert::TryInto;
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Tls {
+    pub sni:  Option<String>,
+    pub alpn: Option<String>,
+}
+
+// Extract SNI and ALPN from a TLS ClientHello at the start of a TCP
+// payload. Truncated messages yield whatever extensions were complete.
+pub fn client_hello(payload: &[u8]) -> Option<Tls> {
+    match payload {
+        &[HANDSHAKE, 3, _, _, _, CLIENT_HELLO, ..] => (),
+        _                                          => return None,
+    }
+
+    let mut n = 9 + 2 + 32;
+    n += 1 + *payload.get(n)? as usize;
+    n += 2 + u16(payload, n)? as usize;
+    n += 1 + *payload.get(n)? as usize;
+    n += 2;
+
+    let mut tls = Tls::default();
+
+    while let (Some(kind), Some(len)) = (u16(payload, n), u16(payload, n+2)) {
+        let data = match payload.get(n+4..n+4+len as usize) {
+            Some(data) => data,
+            None       => break,
+        };
+
+        match kind {
+            SERVER_NAME => tls.sni  = sni(data),
+            ALPN        => tls.alpn = alpn(data),
+            _           => (),
+        }
+
+        n += 4 + len as usize;
+    }
+
+    match tls.sni.is_some() || tls.alpn.is_some() {
+        true  => Some(tls),
+        false => None,
+    }
+}
+
+fn sni(data: &[u8]) -> Option<String> {
+    match data {
+        &[_, _, HOST_NAME, a, b, ref name @ ..] => {
+            let len = u16::from_be_bytes([a, b]) as usize;
+            let name = std::str::from_utf8(name.get(..len)?).ok()?;
+            Some(name.to_ascii_lowercase())
+        },
+        _ => None,
+    }
+}
+
+fn alpn(data: &[u8]) -> Option<String> {
+    let mut protos = Vec::new();
+    let mut data   = data.get(2..)?;
+
+    while let Some((&len, rest)) = data.split_first() {
+        let proto = rest.get(..len as usize)?;
+        protos.push(std::str::from_utf8(proto).ok()?);
+        data = &rest[len as usize..];
+    }
+
+    match protos.is_empty() {
+        true  => None,
+        false => Some(protos.join(",")),
+    }
+}
+
+fn u16(data: &[u8], n: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(n..n+2)?.try_into().ok()?))
+}
+
+const HANDSHAKE:    u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const HOST_NAME:    u8 = 0;
+
+const SERVER_NAME: u16 = 0;
+const ALPN:        u16 = 16;
@@ -7,10 +7,11 @@ use crossbeam_channel::bounded;
 use log::warn;
 use nixv::Version;
 use regex::Regex;
-use signal_hook::{flag::register, consts::signal::{SIGINT, SIGTERM}};
+use signal_hook::{flag::register, consts::signal::{SIGINT, SIGTERM, SIGUSR2}};
 use kentik_api::Client;
 use crate::args::{opt, read};
 use crate::capture::{self, Sample, Sources};
+use crate::dns::Cache;
 use crate::export::Export;
 use crate::link::{Event, Links};
 use crate::sockets::Procs;
@@ -29,33 +30,61 @@ pub fn probe(args: &ArgMatches) -> Result<()> {
     let code = opt(args.value_of("bytecode"))?.map(read).transpose()?;
 
     let interval = value_t!(args, "interval", u64)?;
+    let active   = value_t!(args, "active-timeout", u64)?;
+    let idle     = value_t!(args, "idle-timeout", u64)?;
     let sample   = opt(args.value_of("sample"))?.unwrap_or(Sample::None);
 
     let capture  = value_t!(args, "capture", String)?;
     let exclude  = args.value_of("exclude").unwrap_or("^$");
+    let xdp      = args.value_of("xdp").unwrap_or("^$");
+    let mirror   = args.value_of("mirror").unwrap_or("^$");
+    let packet   = args.value_of("packet").unwrap_or("^$");
+    let fanout   = opt(args.value_of("fanout"))?.unwrap_or_else(num_cpus::get);
+    let biflow   = args.is_present("biflow");
+    let inspect  = args.is_present("inspect");
+    let filter   = args.value_of("filter").map(str::to_owned);
+
+    let profiles = opt(args.value_of("profiles"))?.map(read).transpose()?;
+    let profiles = profiles.as_deref().map(capture::profiles).transpose()?;
 
     let config  = capture::Config {
         capture:     Regex::new(&capture)?,
         exclude:     Regex::new(&exclude)?,
+        xdp:         Regex::new(&xdp)?,
+        mirror:      Regex::new(&mirror)?,
+        packet:      Regex::new(&packet)?,
+        fanout:      fanout,
         interval:    Duration::from_secs(interval),
+        timeouts:    capture::Timeouts {
+            active: Duration::from_secs(active),
+            idle:   Duration::from_secs(idle),
+        },
         buffer_size: 10_000_000,
         sample:      sample,
+        filter:      filter,
         snaplen:     128,
+        inspect:     inspect,
         promisc:     true,
+        biflow:      biflow,
+        profiles:    profiles.unwrap_or_default(),
     };
 
     let shutdown = Arc::new(AtomicBool::new(false));
     register(SIGTERM, shutdown.clone())?;
     register(SIGINT,  shutdown.clone())?;
 
+    let report = Arc::new(AtomicBool::new(false));
+    register(SIGUSR2, report.clone())?;
+
     let client = Client::new(&email, &token, region)?;
 
+    let dns        = Arc::new(Cache::new(true));
     let procs      = Procs::watch(kernel, code, shutdown.clone())?;
     let mut links  = Links::watch(shutdown.clone())?;
-    let mut export = Export::new(client, &device, plan, procs.sockets())?;
+    let mut export = Export::new(client, &device, plan, procs.sockets(), dns.clone())?;
 
     let (tx, rx) = bounded(1_000);
-    let mut sources = Sources::new(config, tx);
+    let mut sources = Sources::new(config, dns, links.locals(), tx);
 
     let timeout = Duration::from_millis(5);
 
@@ -64,12 +93,25 @@ pub fn probe(args: &ArgMatches) -> Result<()> {
             export.export(flows, node.clone())?;
         }
 
+        let mut changed = false;
+
         while let Ok(Some(event)) = links.recv() {
-            match event {
+            changed |= match event {
                 Event::Add(add)       => sources.add(add)?,
                 Event::Delete(link)   => sources.del(link),
-                Event::Error(link, e) => warn!("link {} error: {}", link, e),
-            }
+                Event::Error(link, e) => {
+                    warn!("link {} error: {}", link, e);
+                    false
+                },
+            };
+        }
+
+        if changed {
+            export.interfaces(sources.links());
+        }
+
+        if report.swap(false, Ordering::AcqRel) {
+            sources.report();
         }
     }
 
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use clap::{ArgMatches, value_t};
+use crossbeam_channel::{Receiver, bounded};
+use log::info;
+use pcap::{Capture, Offline};
+use tokio::runtime::Runtime;
+use kentik_api::Client;
+use crate::args::opt;
+use crate::capture::{Datalink, Flow, Random, Sample};
+use crate::capture::queue::Queue;
+use crate::collect::Collect;
+use crate::dns::Cache;
+use crate::export::Export;
+use crate::sockets::Sockets;
+use pcap::Error::*;
+
+pub fn replay(args: &ArgMatches) -> Result<()> {
+    let node     = opt(args.value_of("node"))?;
+    let interval = value_t!(args, "interval", u64)?;
+    let active   = value_t!(args, "active-timeout", u64)?;
+    let idle     = value_t!(args, "idle-timeout", u64)?;
+    let sample   = opt(args.value_of("sample"))?.unwrap_or(Sample::None);
+    let file     = value_t!(args, "file", String)?;
+    let biflow   = args.is_present("biflow");
+    let inspect  = args.is_present("inspect");
+    let hashed   = matches!(sample, Sample::Hash(_));
+
+    let random = match sample {
+        Sample::Rate(n)     => Random::new(n),
+        Sample::Adaptive(_) => return Err(anyhow!("adaptive sampling unsupported in replay")),
+        _                   => Random::new(1),
+    };
+
+    let interval = time::Duration::seconds(interval as i64);
+    let active   = time::Duration::seconds(active as i64);
+    let idle     = time::Duration::seconds(idle as i64);
+    let sample   = sample.rate();
+
+    let cap  = Capture::from_file(&file)?;
+    let link = match Datalink::new(cap.get_datalink().0) {
+        Some(link) => link,
+        None       => return Err(anyhow!("unsupported datalink")),
+    };
+
+    let socks    = Arc::new(Sockets::new());
+    let dns      = Arc::new(Cache::new(true));
+    let mut sink = match args.value_of("agg") {
+        Some(agg) => Sink::collect(agg, socks, dns.clone(), node)?,
+        None      => Sink::export(args, socks, dns.clone(), node)?,
+    };
+
+    let (tx, rx)  = bounded(1_000);
+    let mut queue = Queue::new(None, sample, tx, interval);
+    queue.set_datalink(link);
+    queue.set_hashed(hashed);
+    queue.set_biflow(biflow);
+    queue.set_parse(inspect);
+    queue.set_timeouts(active, idle);
+    queue.set_dns(dns);
+
+    info!("replaying {}", file);
+
+    let count = run(cap, queue, random, rx, |flows| sink.send(flows))?;
+
+    info!("replayed {} flows from {}", count, file);
+
+    sink.finish()
+}
+
+// Feed the kept packets of a capture file through the queue, passing
+// every batch of flows it exports to send, and return the flow count.
+pub fn run<F: FnMut(Vec<Flow>) -> Result<()>>(mut cap: Capture<Offline>, mut queue: Queue, mut random: Random, rx: Receiver<Vec<Flow>>, mut send: F) -> Result<usize> {
+    let mut count = 0;
+
+    loop {
+        match cap.next() {
+            Ok(packet) if random.keep() => queue.record(packet)?,
+            Ok(_)                       => (),
+            Err(NoMorePackets)          => break,
+            Err(e)                      => return Err(e.into()),
+        }
+
+        while let Ok(flows) = rx.try_recv() {
+            count += flows.len();
+            send(flows)?;
+        }
+    }
+
+    queue.flush();
+
+    while let Ok(flows) = rx.try_recv() {
+        count += flows.len();
+        send(flows)?;
+    }
+
+    Ok(count)
+}
+
+enum Sink {
+    Collect(Collect, Runtime),
+    Export(Export, Option<Arc<String>>),
+}
+
+impl Sink {
+    fn collect(agg: &str, socks: Arc<Sockets>, dns: Arc<Cache>, node: Option<String>) -> Result<Self> {
+        let rt      = Runtime::new()?;
+        let collect = Collect::new(agg.to_owned(), socks, dns, &rt, node);
+        Ok(Sink::Collect(collect, rt))
+    }
+
+    fn export(args: &ArgMatches, socks: Arc<Sockets>, dns: Arc<Cache>, node: Option<String>) -> Result<Self> {
+        let email  = value_t!(args, "email",  String)?;
+        let token  = value_t!(args, "token",  String)?;
+        let device = value_t!(args, "device", String)?;
+        let plan   = opt(args.value_of("plan"))?;
+        let region = args.value_of("region");
+
+        let client = Client::new(&email, &token, region)?;
+        let export = Export::new(client, &device, plan, socks, dns)?;
+
+        Ok(Sink::Export(export, node.map(Arc::new)))
+    }
+
+    fn send(&mut self, flows: Vec<Flow>) -> Result<()> {
+        match self {
+            Sink::Collect(collect, _)  => collect.collect(flows),
+            Sink::Export(export, node) => export.export(flows, node.clone()),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Sink::Collect(collect, rt) => collect.finish(&rt),
+            Sink::Export(export, _)    => export.finish(),
+        }
+    }
+}
@@ -29,7 +29,7 @@ pub struct Event {
     pub proc:  Process,
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Kind {
     Connect,
     Accept,
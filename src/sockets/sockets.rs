@@ -4,7 +4,7 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use log::trace;
 use parking_lot::Mutex;
-use crate::capture::flow::{Flow, Protocol};
+use crate::capture::flow::{Biflow, Flow, Protocol};
 use crate::collect::{Meta, Record};
 use super::{Event, Kind, Process};
 
@@ -21,6 +21,7 @@ pub struct Socket {
     proc: Arc<Process>,
     srtt: Duration,
     seen: Instant,
+    role: Option<Kind>,
 }
 
 impl Sockets {
@@ -44,6 +45,13 @@ impl Sockets {
         let now  = Instant::now();
         let srtt = Mutex::new(Duration::from_micros(0));
 
+        let flow = flow.into_iter().map(|mut flow| {
+            if flow.biflow == Biflow::Canonical {
+                initiator(&socks, &mut flow);
+            }
+            flow
+        }).collect::<Vec<_>>();
+
         let mut meta = |key: &Key| {
             let proc = socks.get_mut(key).map(|s| {
                 s.seen = now;
@@ -59,6 +67,7 @@ impl Sockets {
         };
 
         flow.into_iter().map(|flow| {
+            *srtt.lock() = Duration::from_micros(0);
             let src = meta(&Key(flow.protocol, flow.src.addr, flow.src.port));
             let dst = meta(&Key(flow.protocol, flow.dst.addr, flow.dst.port));
             Record {
@@ -81,7 +90,11 @@ impl Sockets {
     }
 
     fn insert(&self, Event { kind, proto, src, dst, proc, srtt, .. }: Event) {
-        let key = Key(proto, src.ip(), src.port());
+        let key  = Key(proto, src.ip(), src.port());
+        let role = match kind {
+            Kind::Connect | Kind::Accept => Some(kind),
+            _                            => None,
+        };
 
         let new = || {
             trace!("{:?} {} -> {}: {} ({})", kind, src, dst, proc.comm, proc.pid);
@@ -89,11 +102,13 @@ impl Sockets {
                 proc: Arc::new(proc),
                 seen: Instant::now(),
                 srtt: srtt,
+                role: role,
             }
         };
 
         self.socks.lock().entry(key).and_modify(|sock| {
             sock.srtt = srtt;
+            sock.role = role.or(sock.role);
         }).or_insert_with(new);
     }
 
@@ -104,3 +119,22 @@ impl Sockets {
         });
     }
 }
+
+// Local sockets know whether they connected or accepted, use that to
+// orient biflows whose handshake was not observed.
+fn initiator(socks: &HashMap<Key, Socket>, flow: &mut Flow) {
+    let role = |addr: IpAddr, port: u16| {
+        socks.get(&Key(flow.protocol, addr, port)).and_then(|s| s.role)
+    };
+
+    let src = role(flow.src.addr, flow.src.port);
+    let dst = role(flow.dst.addr, flow.dst.port);
+
+    match (src, dst) {
+        (Some(Kind::Connect), _) | (_, Some(Kind::Accept)) => (),
+        (Some(Kind::Accept), _) | (_, Some(Kind::Connect)) => flow.flip(),
+        _                                                  => return,
+    }
+
+    flow.biflow = Biflow::Initiator;
+}